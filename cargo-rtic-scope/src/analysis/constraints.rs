@@ -0,0 +1,233 @@
+use crate::analysis::{refers_to, TaskEvent};
+use crate::manifest::TaskConstraints;
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use chrono::{DateTime, Duration, Local};
+
+#[derive(Debug)]
+pub enum ViolationKind {
+    Execution { budget: Duration, actual: Duration },
+    Elapsed { budget: Duration, actual: Duration },
+    Period { budget: Duration, actual: Duration },
+}
+
+/// A task that did not meet its declared timing budget.
+#[derive(Debug)]
+pub struct Violation {
+    pub task: String,
+    /// When the violating invocation was entered.
+    pub at: DateTime<Local>,
+    pub kind: ViolationKind,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let us = |d: &Duration| d.num_nanoseconds().unwrap_or(i64::MAX) as f64 / 1e3;
+        let (what, budget, actual, cmp) = match &self.kind {
+            ViolationKind::Execution { budget, actual } => {
+                ("executed for", budget, actual, "exceeding its budget of")
+            }
+            ViolationKind::Elapsed { budget, actual } => (
+                "took",
+                budget,
+                actual,
+                "from entry to exit, exceeding its budget of",
+            ),
+            ViolationKind::Period { budget, actual } => (
+                "was entered again after",
+                budget,
+                actual,
+                "below its minimum period of",
+            ),
+        };
+        write!(
+            f,
+            "{}: task {} {} {:.3} µs, {} {:.3} µs",
+            self.at,
+            self.task,
+            what,
+            us(actual),
+            cmp,
+            us(budget),
+        )
+    }
+}
+
+/// Checks task events against the timing budgets declared in the
+/// manifest.
+pub struct ConstraintChecker {
+    constraints: BTreeMap<String, TaskConstraints>,
+    last_entered: BTreeMap<String, DateTime<Local>>,
+    violations: usize,
+}
+
+impl ConstraintChecker {
+    pub fn new(constraints: BTreeMap<String, TaskConstraints>) -> Self {
+        Self {
+            constraints,
+            last_entered: BTreeMap::new(),
+            violations: 0,
+        }
+    }
+
+    /// Finds the constraints of a task. Constraints may be declared
+    /// under the full task name (e.g. `app::blink`) or only the name of
+    /// the task function (e.g. `blink`).
+    fn constraints_of(&self, task: &str) -> Option<&TaskConstraints> {
+        self.constraints.get(task).or_else(|| {
+            self.constraints
+                .iter()
+                .find(|(name, _)| refers_to(name, task))
+                .map(|(_, c)| c)
+        })
+    }
+
+    /// Checks the given task events, as found by a
+    /// [crate::analysis::TaskTracker], and returns the violations among
+    /// them.
+    pub fn check(&mut self, events: &[TaskEvent]) -> Vec<Violation> {
+        let us = |us: u64| Duration::microseconds(us as i64);
+        let mut violations = vec![];
+
+        for event in events {
+            match event {
+                TaskEvent::Entered { task, at } => {
+                    let last = self.last_entered.insert(task.clone(), *at);
+                    if let (Some(last), Some(min_period)) = (
+                        last,
+                        self.constraints_of(task).and_then(|c| c.min_period_us),
+                    ) {
+                        let period = *at - last;
+                        if period < us(min_period) {
+                            violations.push(Violation {
+                                task: task.clone(),
+                                at: *at,
+                                kind: ViolationKind::Period {
+                                    budget: us(min_period),
+                                    actual: period,
+                                },
+                            });
+                        }
+                    }
+                }
+                TaskEvent::Resumed { .. } => (),
+                TaskEvent::Completed(invocation) => {
+                    let constraints = match self.constraints_of(&invocation.task) {
+                        Some(c) => c,
+                        None => continue,
+                    };
+                    let violation = |kind| Violation {
+                        task: invocation.task.clone(),
+                        at: invocation.entered,
+                        kind,
+                    };
+
+                    if let Some(max) = constraints.max_exec_us {
+                        if invocation.execution_time > us(max) {
+                            violations.push(violation(ViolationKind::Execution {
+                                budget: us(max),
+                                actual: invocation.execution_time,
+                            }));
+                        }
+                    }
+                    if let Some(max) = constraints.max_elapsed_us {
+                        let elapsed_time = invocation.elapsed_time();
+                        if elapsed_time > us(max) {
+                            violations.push(violation(ViolationKind::Elapsed {
+                                budget: us(max),
+                                actual: elapsed_time,
+                            }));
+                        }
+                    }
+                }
+            }
+        }
+
+        self.violations += violations.len();
+        violations
+    }
+
+    /// Total number of violations found thus far.
+    pub fn violations(&self) -> usize {
+        self.violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::tests::{chunk, track};
+
+    use rtic_scope_api::TaskAction::*;
+
+    #[test]
+    fn violations() {
+        let start = Local::now();
+        let mut constraints = BTreeMap::new();
+        constraints.insert(
+            "a".to_string(),
+            TaskConstraints {
+                max_exec_us: Some(25),
+                max_elapsed_us: Some(40),
+                min_period_us: Some(100),
+            },
+        );
+        let mut checker = ConstraintChecker::new(constraints);
+
+        let violations: Vec<Violation> = track(vec![
+            // Executes for 30 µs over 50 µs: both budgets are exceeded.
+            chunk(start, 0, vec![("app::a", Entered)]),
+            chunk(start, 10, vec![("app::b", Entered)]),
+            chunk(start, 30, vec![("app::b", Exited), ("app::a", Returned)]),
+            chunk(start, 50, vec![("app::a", Exited)]),
+            // Executes for 20 µs over 40 µs: time spent preempted does
+            // not count towards the execution budget.
+            chunk(start, 120, vec![("app::a", Entered)]),
+            chunk(start, 125, vec![("app::b", Entered)]),
+            chunk(start, 145, vec![("app::b", Exited)]),
+            chunk(start, 160, vec![("app::a", Exited)]),
+            // Entered 80 µs after the last entry.
+            chunk(start, 200, vec![("app::a", Entered)]),
+            chunk(start, 210, vec![("app::a", Exited)]),
+        ])
+        .into_iter()
+        .flat_map(|(_, events)| checker.check(&events))
+        .collect();
+
+        let us = Duration::microseconds;
+        assert_eq!(violations.len(), 3);
+        assert!(violations.iter().all(|v| v.task == "app::a"));
+        assert!(matches!(
+            violations[0].kind,
+            ViolationKind::Execution { budget, actual } if budget == us(25) && actual == us(30)
+        ));
+        assert!(matches!(
+            violations[1].kind,
+            ViolationKind::Elapsed { budget, actual } if budget == us(40) && actual == us(50)
+        ));
+        assert_eq!(violations[1].at, start);
+        assert!(matches!(
+            violations[2].kind,
+            ViolationKind::Period { budget, actual } if budget == us(100) && actual == us(80)
+        ));
+        assert_eq!(violations[2].at, start + us(200));
+        assert_eq!(checker.violations(), 3);
+    }
+
+    #[test]
+    fn unconstrained_tasks() {
+        let start = Local::now();
+        let mut checker = ConstraintChecker::new(BTreeMap::new());
+
+        for (_, events) in track(vec![
+            chunk(start, 0, vec![("app::a", Entered)]),
+            chunk(start, 1_000_000, vec![("app::a", Exited)]),
+            chunk(start, 1_000_001, vec![("app::a", Entered)]),
+        ]) {
+            assert!(checker.check(&events).is_empty());
+        }
+        assert_eq!(checker.violations(), 0);
+    }
+}
@@ -0,0 +1,269 @@
+//! Analysis of the task events recovered from the trace stream.
+use chrono::{DateTime, Duration, Local};
+use rtic_scope_api::{EventChunk, EventType, TaskAction};
+
+mod constraints;
+mod session;
+mod stats;
+pub use constraints::ConstraintChecker;
+pub use session::SessionAnalysis;
+pub use stats::{Report, Statistics};
+
+/// Returns whether `name` refers to `task`, either by its full name
+/// (e.g. `app::blink`) or only by the name of the task function (e.g.
+/// `blink`).
+pub fn refers_to(name: &str, task: &str) -> bool {
+    name == task || task.rsplit("::").next() == Some(name)
+}
+
+/// A task that has been entered but not yet exited.
+struct ActiveTask {
+    name: String,
+    entered: DateTime<Local>,
+    execution_time: Duration,
+}
+
+/// A completed execution of a task.
+pub struct Invocation {
+    pub task: String,
+    pub entered: DateTime<Local>,
+    pub exited: DateTime<Local>,
+    /// Time spent executing, excluding any time the task was
+    /// preempted.
+    pub execution_time: Duration,
+}
+
+impl Invocation {
+    /// Time from task entry to exit, including any time the task was
+    /// preempted. As task releases are not traced, this excludes the
+    /// time the task waited to be entered, i.e. it is not the response
+    /// time of the task.
+    pub fn elapsed_time(&self) -> Duration {
+        self.exited - self.entered
+    }
+}
+
+pub enum TaskEvent {
+    Entered {
+        task: String,
+        at: DateTime<Local>,
+    },
+    /// A preempted task was returned to.
+    Resumed {
+        task: String,
+        at: DateTime<Local>,
+    },
+    Completed(Invocation),
+}
+
+/// Follows task entries and exits to track which task is executing on
+/// the target at any moment. Tasks entered while another is active are
+/// considered to preempt it.
+#[derive(Default)]
+pub struct TaskTracker {
+    /// Currently entered tasks; the executing one last.
+    stack: Vec<ActiveTask>,
+    last_timestamp: Option<DateTime<Local>>,
+}
+
+impl TaskTracker {
+    /// Feeds the next chunk of events to the tracker, returning the
+    /// task entries and completed invocations it contained.
+    pub fn update(&mut self, chunk: &EventChunk) -> Vec<TaskEvent> {
+        let ts = chunk.timestamp.ts;
+
+        // Attribute the time since the last chunk to the executing
+        // task. Diverged timestamps may run backwards; such time is
+        // ignored.
+        if let (Some(last), Some(active)) = (self.last_timestamp, self.stack.last_mut()) {
+            if ts > last {
+                active.execution_time = active.execution_time + (ts - last);
+            }
+        }
+        self.last_timestamp = Some(ts);
+
+        let mut events = vec![];
+        for event in chunk.events.iter() {
+            if let EventType::Task { name, action } = event {
+                match action {
+                    TaskAction::Entered => {
+                        self.stack.push(ActiveTask {
+                            name: name.clone(),
+                            entered: ts,
+                            execution_time: Duration::zero(),
+                        });
+                        events.push(TaskEvent::Entered {
+                            task: name.clone(),
+                            at: ts,
+                        });
+                    }
+                    TaskAction::Exited => {
+                        // Tasks entered before the trace started
+                        // cannot be accounted for. Any tasks above the
+                        // exited one were missed exiting.
+                        if let Some(pos) = self.stack.iter().rposition(|t| &t.name == name) {
+                            let task = self.stack.drain(pos..).next().unwrap();
+                            events.push(TaskEvent::Completed(Invocation {
+                                task: task.name,
+                                entered: task.entered,
+                                exited: ts,
+                                execution_time: task.execution_time,
+                            }));
+                        }
+                    }
+                    TaskAction::Returned => {
+                        // Any tasks above the returned-to one were
+                        // missed exiting. Returns to tasks entered
+                        // before the trace started, and to thread
+                        // mode, cannot be accounted for.
+                        if let Some(pos) = self.stack.iter().rposition(|t| &t.name == name) {
+                            self.stack.truncate(pos + 1);
+                            events.push(TaskEvent::Resumed {
+                                task: name.clone(),
+                                at: ts,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A chunk of the given task actions, `us` microseconds after
+    /// `start`.
+    pub fn chunk(start: DateTime<Local>, us: i64, actions: Vec<(&str, TaskAction)>) -> EventChunk {
+        EventChunk {
+            timestamp: rtic_scope_api::Timestamp {
+                ts: start + Duration::microseconds(us),
+                data_relation: None,
+                diverged: false,
+            },
+            events: actions
+                .into_iter()
+                .map(|(name, action)| EventType::Task {
+                    name: name.to_string(),
+                    action,
+                })
+                .collect(),
+        }
+    }
+
+    /// Feeds the given chunks to a [TaskTracker], pairing each chunk
+    /// with the task events found in it.
+    pub fn track(chunks: Vec<EventChunk>) -> Vec<(EventChunk, Vec<TaskEvent>)> {
+        let mut tracker = TaskTracker::default();
+        chunks
+            .into_iter()
+            .map(|chunk| {
+                let events = tracker.update(&chunk);
+                (chunk, events)
+            })
+            .collect()
+    }
+
+    fn completed(events: &[(EventChunk, Vec<TaskEvent>)]) -> Vec<&Invocation> {
+        events
+            .iter()
+            .flat_map(|(_, events)| events.iter())
+            .filter_map(|event| match event {
+                TaskEvent::Completed(invocation) => Some(invocation),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn returned_task_resumes() {
+        use TaskAction::*;
+        let start = Local::now();
+        let events = track(vec![
+            chunk(start, 0, vec![("a", Entered)]),
+            chunk(start, 10, vec![("b", Entered)]),
+            chunk(start, 20, vec![("c", Entered)]),
+            // c is missed exiting
+            chunk(start, 30, vec![("b", Exited), ("a", Returned)]),
+            chunk(start, 40, vec![("a", Exited)]),
+        ]);
+
+        let resumed: Vec<_> = events
+            .iter()
+            .flat_map(|(_, events)| events.iter())
+            .filter_map(|event| match event {
+                TaskEvent::Resumed { task, at } => Some((task.as_str(), *at)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(resumed, [("a", start + Duration::microseconds(30))]);
+
+        let invocations = completed(&events);
+        assert_eq!(invocations.len(), 2);
+        assert_eq!(invocations[0].task, "b");
+        assert_eq!(invocations[1].task, "a");
+        assert_eq!(invocations[1].execution_time, Duration::microseconds(20));
+    }
+
+    #[test]
+    fn preemption_is_excluded_from_execution_time() {
+        use TaskAction::*;
+        let start = Local::now();
+        let events = track(vec![
+            chunk(start, 0, vec![("a", Entered)]),
+            chunk(start, 10, vec![("b", Entered)]),
+            chunk(start, 15, vec![("c", Entered)]),
+            chunk(start, 20, vec![("c", Exited)]),
+            chunk(start, 30, vec![("b", Exited)]),
+            chunk(start, 50, vec![("a", Exited)]),
+        ]);
+
+        let invocations = completed(&events);
+        let times: Vec<_> = invocations
+            .iter()
+            .map(|i| (i.task.as_str(), i.execution_time, i.elapsed_time()))
+            .collect();
+        let us = Duration::microseconds;
+        assert_eq!(
+            times,
+            [
+                ("c", us(5), us(5)),
+                ("b", us(15), us(20)),
+                ("a", us(30), us(50)),
+            ]
+        );
+    }
+
+    #[test]
+    fn exits_of_unknown_tasks_are_ignored() {
+        use TaskAction::*;
+        let start = Local::now();
+        let events = track(vec![
+            // Entered before the trace started
+            chunk(start, 0, vec![("a", Exited)]),
+            chunk(start, 10, vec![("b", Entered)]),
+            chunk(start, 20, vec![("a", Exited), ("b", Exited)]),
+        ]);
+
+        let invocations = completed(&events);
+        assert_eq!(invocations.len(), 1);
+        assert_eq!(invocations[0].task, "b");
+        assert_eq!(invocations[0].execution_time, Duration::microseconds(10));
+    }
+
+    #[test]
+    fn returns_to_unknown_tasks_are_ignored() {
+        let start = Local::now();
+        let events = track(vec![chunk(
+            start,
+            0,
+            vec![("ThreadMode", TaskAction::Returned)],
+        )]);
+
+        assert!(events[0].1.is_empty());
+    }
+}
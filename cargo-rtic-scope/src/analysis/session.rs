@@ -0,0 +1,153 @@
+use crate::analysis::{refers_to, ConstraintChecker, Report, Statistics, TaskEvent, TaskTracker};
+use crate::log;
+use crate::manifest::TaskConstraints;
+use crate::sinks::{send_or_halt, Packets};
+
+use std::collections::BTreeMap;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    mpsc, Arc,
+};
+use std::thread;
+use std::time::Duration;
+
+use rtic_scope_api::EventType;
+
+/// Number of packet groups that may be queued for analysis before the
+/// forwarding of further packet groups is paused.
+const QUEUE_LEN: usize = 4096;
+
+/// Running counts of a [SessionAnalysis], read while the session is
+/// ongoing.
+#[derive(Default)]
+pub struct Counters {
+    pub malformed: AtomicUsize,
+    pub nonmappable: AtomicUsize,
+    /// Entries of the task given to [SessionAnalysis::spawn], if any.
+    pub task_entries: AtomicUsize,
+}
+
+/// Analyzes the packet groups of a session on a dedicated thread: per-task
+/// timing statistics, timing constraint checks, and warnings about
+/// packets that cannot be mapped. The RTIC events of a packet group are
+/// thus mapped concurrently with, and shared by, the sinks that need
+/// them, rather than before the packet group is forwarded. If the
+/// analysis falls behind, no further packet groups are forwarded until it
+/// has caught up: the time spent waiting is reported in
+/// [SessionAnalysis::stalled]. No packet group is dropped, unless the
+/// session is halted meanwhile.
+pub struct SessionAnalysis {
+    tx: mpsc::SyncSender<Arc<Packets>>,
+    halt: Arc<AtomicBool>,
+    handle: thread::JoinHandle<(Report, usize)>,
+    counters: Arc<Counters>,
+    stalled: Duration,
+}
+
+impl SessionAnalysis {
+    /// Spawns the analysis. Entries of `stop_on_task`, if given, are
+    /// counted in [Counters::task_entries]. Queueing is abandoned once
+    /// `halt` is set.
+    pub fn spawn(
+        constraints: BTreeMap<String, TaskConstraints>,
+        stop_on_task: Option<String>,
+        halt: Arc<AtomicBool>,
+    ) -> Self {
+        let (tx, rx) = mpsc::sync_channel::<Arc<Packets>>(QUEUE_LEN);
+        let counters = Arc::new(Counters::default());
+
+        let handle = {
+            let counters = counters.clone();
+            thread::spawn(move || {
+                let mut tracker = TaskTracker::default();
+                let mut statistics = Statistics::default();
+                let mut checker = ConstraintChecker::new(constraints);
+
+                for packets in rx {
+                    let chunk = packets.chunk();
+                    let task_events = tracker.update(chunk);
+                    if let Some(stop_task) = &stop_on_task {
+                        let entries = task_events
+                            .iter()
+                            .filter(|event| match event {
+                                TaskEvent::Entered { task, .. } => refers_to(stop_task, task),
+                                _ => false,
+                            })
+                            .count();
+                        counters.task_entries.fetch_add(entries, Ordering::SeqCst);
+                    }
+                    statistics.update(chunk, &task_events);
+                    for violation in checker.check(&task_events) {
+                        log::warn(format!("timing violation at {}", violation));
+                    }
+
+                    // Report any unmappable/unknown events that occured
+                    for event in chunk.events.iter() {
+                        match event {
+                            EventType::Unmappable(ref packet, ref reason) => {
+                                counters.nonmappable.fetch_add(1, Ordering::SeqCst);
+                                log::warn(format!("cannot map {:?} packet: {}", packet, reason));
+                            }
+                            EventType::Unknown(ref packet) => {
+                                counters.nonmappable.fetch_add(1, Ordering::SeqCst);
+                                log::warn(format!("cannot map {:?} packet", packet));
+                            }
+                            EventType::Invalid(ref malformed) => {
+                                counters.malformed.fetch_add(1, Ordering::SeqCst);
+                                log::warn(format!("malformed packet: {}: {:?}", malformed, malformed));
+                            }
+                            EventType::Overflow => log::warn("Overflow detected! Packets may have been dropped and timestamps will be diverged until the next global timestamp".to_string()),
+                            _ => (),
+                        }
+                    }
+                }
+
+                (statistics.report(), checker.violations())
+            })
+        };
+
+        Self {
+            tx,
+            halt,
+            handle,
+            counters,
+            stalled: Duration::ZERO,
+        }
+    }
+
+    /// Queues a packet group for analysis, waiting for the analysis to
+    /// catch up if the queue is full.
+    pub fn send(&mut self, packets: Arc<Packets>) {
+        // NOTE the analysis thread only exits once the queue is closed,
+        // or if it panicked, which is reported on finish.
+        if let Ok(Some(stall)) = send_or_halt(&self.tx, packets, &self.halt) {
+            if self.stalled == Duration::ZERO {
+                log::warn(
+                    "session analysis cannot keep up; trace data is not forwarded until it catches up"
+                        .to_string(),
+                );
+            }
+            self.stalled += stall;
+        }
+    }
+
+    /// Time spent waiting for the analysis to catch up with a full queue.
+    pub fn stalled(&self) -> Duration {
+        self.stalled
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// Closes the queue and waits for the analysis of the queued packet
+    /// groups. Returns the timing statistics of the session and the
+    /// number of timing constraint violations.
+    pub fn finish(self) -> (Report, usize) {
+        drop(self.tx);
+        self.handle.join().unwrap_or_else(|_| {
+            log::err("session analysis panicked".to_string());
+            (Statistics::default().report(), 0)
+        })
+    }
+}
@@ -0,0 +1,298 @@
+use crate::analysis::TaskEvent;
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use chrono::{DateTime, Duration, Local};
+use rtic_scope_api::EventChunk;
+use serde::Serialize;
+
+/// Samples collected for a single task, in nanoseconds.
+#[derive(Default)]
+struct TaskSamples {
+    execution_times: Vec<i64>,
+    elapsed_times: Vec<i64>,
+    inter_arrival_times: Vec<i64>,
+    last_entered: Option<DateTime<Local>>,
+    preemptions: usize,
+}
+
+/// Collects per-task timing statistics from the event stream.
+#[derive(Default)]
+pub struct Statistics {
+    tasks: BTreeMap<String, TaskSamples>,
+    first_timestamp: Option<DateTime<Local>>,
+    last_timestamp: Option<DateTime<Local>>,
+}
+
+fn nanos(d: Duration) -> i64 {
+    d.num_nanoseconds().unwrap_or(i64::MAX).max(0)
+}
+
+impl Statistics {
+    /// Records the task events of the given chunk, as found by a
+    /// [crate::analysis::TaskTracker].
+    pub fn update(&mut self, chunk: &EventChunk, events: &[TaskEvent]) {
+        let ts = chunk.timestamp.ts;
+        self.first_timestamp.get_or_insert(ts);
+        self.last_timestamp = Some(self.last_timestamp.map_or(ts, |last| last.max(ts)));
+
+        for event in events {
+            match event {
+                TaskEvent::Entered { task, at } => {
+                    let samples = self.tasks.entry(task.clone()).or_default();
+                    if let Some(last) = samples.last_entered.replace(*at) {
+                        samples.inter_arrival_times.push(nanos(*at - last));
+                    }
+                }
+                TaskEvent::Resumed { task, .. } => {
+                    self.tasks.entry(task.clone()).or_default().preemptions += 1;
+                }
+                TaskEvent::Completed(invocation) => {
+                    let samples = self.tasks.entry(invocation.task.clone()).or_default();
+                    samples
+                        .execution_times
+                        .push(nanos(invocation.execution_time));
+                    samples.elapsed_times.push(nanos(invocation.elapsed_time()));
+                }
+            }
+        }
+    }
+
+    pub fn report(&self) -> Report {
+        let span = match (self.first_timestamp, self.last_timestamp) {
+            (Some(first), Some(last)) => nanos(last - first),
+            _ => 0,
+        };
+
+        Report {
+            span_us: span as f64 / 1e3,
+            tasks: self
+                .tasks
+                .iter()
+                .map(|(name, samples)| TaskStatistics {
+                    task: name.clone(),
+                    invocations: samples.execution_times.len(),
+                    execution_time_us: Summary::new(&samples.execution_times),
+                    elapsed_time_us: Summary::new(&samples.elapsed_times),
+                    inter_arrival_time_us: Summary::new(&samples.inter_arrival_times),
+                    preemptions: samples.preemptions,
+                    cpu_share: if span > 0 {
+                        samples
+                            .execution_times
+                            .iter()
+                            .map(|t| *t as f64)
+                            .sum::<f64>()
+                            / span as f64
+                    } else {
+                        0.0
+                    },
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Distribution of a set of durations, in microseconds.
+#[derive(Serialize)]
+pub struct Summary {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+impl Summary {
+    /// Summarizes the given samples in nanoseconds. Returns `None` if
+    /// there are no samples.
+    fn new(samples: &[i64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted = samples.to_vec();
+        sorted.sort_unstable();
+        // Nearest-rank percentile
+        let percentile = |p: f64| {
+            let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+            sorted[rank.max(1) - 1] as f64 / 1e3
+        };
+
+        Some(Self {
+            min: sorted[0] as f64 / 1e3,
+            max: sorted[sorted.len() - 1] as f64 / 1e3,
+            mean: sorted.iter().map(|s| *s as f64).sum::<f64>() / sorted.len() as f64 / 1e3,
+            p50: percentile(50.0),
+            p95: percentile(95.0),
+            p99: percentile(99.0),
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct TaskStatistics {
+    pub task: String,
+    /// Number of completed invocations.
+    pub invocations: usize,
+    pub execution_time_us: Option<Summary>,
+    pub elapsed_time_us: Option<Summary>,
+    pub inter_arrival_time_us: Option<Summary>,
+    /// Number of times the task was returned to after being preempted.
+    pub preemptions: usize,
+    /// Fraction of the session spent executing the task.
+    pub cpu_share: f64,
+}
+
+/// Per-task timing statistics of a trace session.
+#[derive(Serialize)]
+pub struct Report {
+    /// Time from the first to the last event of the session.
+    pub span_us: f64,
+    pub tasks: Vec<TaskStatistics>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self
+            .tasks
+            .iter()
+            .map(|t| t.task.len())
+            .chain(std::iter::once("task".len()))
+            .max()
+            .unwrap();
+        let us = |s: &Option<Summary>, field: fn(&Summary) -> f64| match s {
+            Some(s) => format!("{:.3}", field(s)),
+            None => "-".to_string(),
+        };
+
+        writeln!(
+            f,
+            "{:<width$} {:>8} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12} {:>8} {:>7}",
+            "task",
+            "count",
+            "exec min",
+            "exec mean",
+            "exec p95",
+            "exec max",
+            "elapsed p95",
+            "elapsed max",
+            "period min",
+            "preempt",
+            "cpu %",
+            width = width,
+        )?;
+        for t in self.tasks.iter() {
+            writeln!(
+                f,
+                "{:<width$} {:>8} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12} {:>8} {:>7.2}",
+                t.task,
+                t.invocations,
+                us(&t.execution_time_us, |s| s.min),
+                us(&t.execution_time_us, |s| s.mean),
+                us(&t.execution_time_us, |s| s.p95),
+                us(&t.execution_time_us, |s| s.max),
+                us(&t.elapsed_time_us, |s| s.p95),
+                us(&t.elapsed_time_us, |s| s.max),
+                us(&t.inter_arrival_time_us, |s| s.min),
+                t.preemptions,
+                t.cpu_share * 100.0,
+                width = width,
+            )?;
+        }
+        write!(
+            f,
+            "(times in µs; {} task(s) over {:.3} ms)",
+            self.tasks.len(),
+            self.span_us / 1e3
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::tests::{chunk, track};
+
+    use chrono::Local;
+    use rtic_scope_api::TaskAction::*;
+
+    /// Two invocations of `a`, the second of which is preempted by `b`.
+    fn report() -> Report {
+        let start = Local::now();
+        let mut statistics = Statistics::default();
+        for (chunk, events) in track(vec![
+            chunk(start, 0, vec![("a", Entered)]),
+            chunk(start, 10, vec![("a", Exited)]),
+            chunk(start, 100, vec![("a", Entered)]),
+            chunk(start, 110, vec![("b", Entered)]),
+            chunk(start, 140, vec![("b", Exited), ("a", Returned)]),
+            chunk(start, 150, vec![("a", Exited)]),
+        ]) {
+            statistics.update(&chunk, &events);
+        }
+
+        statistics.report()
+    }
+
+    #[test]
+    fn per_task_statistics() {
+        let report = report();
+        assert_eq!(report.span_us, 150.0);
+        assert_eq!(report.tasks.len(), 2);
+
+        let a = &report.tasks[0];
+        assert_eq!(a.task, "a");
+        assert_eq!(a.invocations, 2);
+        assert_eq!(a.preemptions, 1);
+        let exec = a.execution_time_us.as_ref().unwrap();
+        assert_eq!((exec.min, exec.mean, exec.max), (10.0, 15.0, 20.0));
+        let elapsed = a.elapsed_time_us.as_ref().unwrap();
+        assert_eq!((elapsed.min, elapsed.max), (10.0, 50.0));
+        assert_eq!(a.inter_arrival_time_us.as_ref().unwrap().min, 100.0);
+        assert!((a.cpu_share - 0.2).abs() < 1e-9);
+
+        let b = &report.tasks[1];
+        assert_eq!(b.task, "b");
+        assert_eq!(b.invocations, 1);
+        assert_eq!(b.preemptions, 0);
+        assert_eq!(b.execution_time_us.as_ref().unwrap().max, 30.0);
+        assert!(b.inter_arrival_time_us.is_none());
+        assert!((b.cpu_share - 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn percentiles() {
+        let samples: Vec<i64> = (1..=100).rev().map(|us| us * 1000).collect();
+        let summary = Summary::new(&samples).unwrap();
+
+        assert_eq!((summary.min, summary.max, summary.mean), (1.0, 100.0, 50.5));
+        assert_eq!((summary.p50, summary.p95, summary.p99), (50.0, 95.0, 99.0));
+        assert!(Summary::new(&[]).is_none());
+    }
+
+    #[test]
+    fn table() {
+        let table = report().to_string();
+        let lines: Vec<_> = table.lines().collect();
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("task "));
+        assert_eq!(
+            lines[1].split_whitespace().collect::<Vec<_>>(),
+            [
+                "a", "2", "10.000", "15.000", "20.000", "20.000", "50.000", "50.000", "100.000",
+                "1", "20.00"
+            ]
+        );
+        assert_eq!(
+            lines[2].split_whitespace().collect::<Vec<_>>(),
+            [
+                "b", "1", "30.000", "30.000", "30.000", "30.000", "30.000", "30.000", "-", "0",
+                "20.00"
+            ]
+        );
+        assert_eq!(lines[3], "(times in µs; 2 task(s) over 0.150 ms)");
+    }
+}
@@ -0,0 +1,90 @@
+//! Verification that a running target executes the built firmware, so
+//! that it can be traced without being flashed and reset.
+use crate::diag;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use object::elf::{FileHeader32, PT_LOAD};
+use object::read::elf::{FileHeader, ProgramHeader};
+use object::Endianness;
+use probe_rs::{MemoryInterface, Session};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AttachError {
+    #[error("Failed to read {}: {1}", .0.display())]
+    ReadElf(PathBuf, #[source] std::io::Error),
+    #[error("Failed to parse {}: {1}", .0.display())]
+    ParseElf(PathBuf, #[source] object::read::Error),
+    #[error("{} contains no loadable segments", .0.display())]
+    NoSegments(PathBuf),
+    #[error("{} is truncated", .0.display())]
+    TruncatedElf(PathBuf),
+    #[error("Failed to read target memory: {0}")]
+    ReadMemory(#[source] probe_rs::Error),
+    #[error("The firmware on the target does not match {}: the {1}-byte segment at {2:#010x} has CRC32 {3:#010x} on the target, but {4:#010x} in the ELF", .0.display())]
+    FirmwareMismatch(PathBuf, usize, u32, u32, u32),
+}
+
+impl diag::DiagnosableError for AttachError {
+    fn diagnose(&self) -> Vec<String> {
+        match self {
+            Self::FirmwareMismatch(..) => vec![
+                "Build the firmware that runs on the target with the same sources, profile, and features, e.g. by checking out the revision it was built from.".to_string(),
+                "Omit --attach to flash the built firmware and reset the target instead.".to_string(),
+            ],
+            _ => vec![],
+        }
+    }
+}
+
+/// Verifies that the loadable segments of the given ELF are present in
+/// the memory of the target by comparing their CRC32 checksums. The
+/// target is not halted.
+pub fn verify_firmware(session: &mut Session, elf: &Path) -> Result<(), AttachError> {
+    let parse_err = |e: object::read::Error| AttachError::ParseElf(elf.to_path_buf(), e);
+
+    let bytes = fs::read(elf).map_err(|e| AttachError::ReadElf(elf.to_path_buf(), e))?;
+    let header = FileHeader32::<Endianness>::parse(&*bytes).map_err(parse_err)?;
+    let endian = header.endian().map_err(parse_err)?;
+
+    let mut segments = vec![];
+    for segment in header.program_headers(endian, &*bytes).map_err(parse_err)? {
+        if segment.p_type(endian) != PT_LOAD || segment.p_filesz(endian) == 0 {
+            continue;
+        }
+
+        // Segments are loaded at their physical address. For .data,
+        // this is where its initial values reside in flash; they are
+        // only copied to RAM on boot.
+        let offset = segment.p_offset(endian) as usize;
+        let data = bytes
+            .get(offset..offset + segment.p_filesz(endian) as usize)
+            .ok_or_else(|| AttachError::TruncatedElf(elf.to_path_buf()))?;
+        segments.push((segment.p_paddr(endian), data));
+    }
+    if segments.is_empty() {
+        return Err(AttachError::NoSegments(elf.to_path_buf()));
+    }
+
+    let mut core = session.core(0).map_err(AttachError::ReadMemory)?;
+    for (addr, expected) in segments {
+        let mut actual = vec![0; expected.len()];
+        core.read_8(addr, &mut actual)
+            .map_err(AttachError::ReadMemory)?;
+
+        let (expected_crc, actual_crc) = (crc32fast::hash(expected), crc32fast::hash(&actual));
+        if expected_crc != actual_crc {
+            return Err(AttachError::FirmwareMismatch(
+                elf.to_path_buf(),
+                expected.len(),
+                addr,
+                actual_crc,
+                expected_crc,
+            ));
+        }
+    }
+
+    Ok(())
+}
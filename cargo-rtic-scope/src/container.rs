@@ -0,0 +1,247 @@
+//! Binary container format of recorded trace files.
+//!
+//! A trace file starts with [MAGIC] followed by the little-endian
+//! [FORMAT_VERSION] it was written with. The remainder of the file is a
+//! sequence of records, each on the form
+//!
+//! ```text
+//! | kind: u8 | length: u32 (LE) | crc: u32 (LE) | payload: [u8; length] |
+//! ```
+//!
+//! where `crc` is the CRC-32 of `kind`, `length` and `payload`. A record
+//! that fails its checksum, or that is cut short because the recording
+//! was interrupted, marks the end of the intact part of the file.
+//! Version 1 files lack the `crc` field.
+//!
+//! The first record is always a [RecordKind::Metadata] record which
+//! holds the JSON-serialized [crate::recovery::Metadata]. JSON is used
+//! so that fields added to the metadata in the future do not break
+//! older files. Following [RecordKind::Packets] records hold
+//! bincode-serialized [crate::TraceData], and [RecordKind::Raw] records
+//! hold the raw trace stream bytes the packets were decoded from, if
+//! they were recorded. Raw records precede the packets decoded from
+//! them. Readers skip record kinds they do not know.
+//!
+//! Files written before this format was introduced are concatenated
+//! JSON objects and do not start with [MAGIC].
+//!
+//! Either format may be wrapped in a zstd stream in its entirety, in
+//! which case the file is suffixed `.trace.zst`.
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+
+/// Identifies a binary trace file.
+pub const MAGIC: &[u8; 8] = b"RTICSCOP";
+
+/// Version of the container format written by this build. Must be
+/// bumped on any change to the layout of the file or its records.
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Upper bound on the payload of a single record. A larger length can
+/// only stem from corruption.
+const MAX_RECORD_LEN: u32 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordKind {
+    Metadata,
+    Packets,
+    Raw,
+    /// A record kind unknown to this build. Skipped when read.
+    Unknown(u8),
+}
+
+impl From<u8> for RecordKind {
+    fn from(b: u8) -> Self {
+        match b {
+            0 => Self::Metadata,
+            1 => Self::Packets,
+            2 => Self::Raw,
+            b => Self::Unknown(b),
+        }
+    }
+}
+
+impl From<RecordKind> for u8 {
+    fn from(kind: RecordKind) -> Self {
+        match kind {
+            RecordKind::Metadata => 0,
+            RecordKind::Packets => 1,
+            RecordKind::Raw => 2,
+            RecordKind::Unknown(b) => b,
+        }
+    }
+}
+
+/// Frame magic number of a zstd stream, as written by a compressing
+/// [crate::sinks::FileSink].
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Returns whether `bytes` is the start of a compressed trace file.
+pub fn is_compressed(bytes: &[u8]) -> bool {
+    bytes.starts_with(&ZSTD_MAGIC)
+}
+
+/// Returns whether `bytes` is the start of a binary trace file.
+pub fn has_magic(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn write_header<W: Write>(w: &mut W) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&FORMAT_VERSION.to_le_bytes())
+}
+
+/// Reads the file header and returns the format version of the file.
+pub fn read_header<R: Read + ?Sized>(r: &mut R) -> io::Result<u16> {
+    let mut magic = [0; MAGIC.len()];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an RTIC Scope trace file",
+        ));
+    }
+
+    let mut version = [0; 2];
+    r.read_exact(&mut version)?;
+    Ok(u16::from_le_bytes(version))
+}
+
+/// Size in bytes of the framing around each record payload in a file
+/// of the given format version.
+pub fn record_overhead(version: u16) -> u64 {
+    match version {
+        1 => 5,
+        _ => 9,
+    }
+}
+
+fn checksum(kind: u8, len: &[u8; 4], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[kind]);
+    hasher.update(len);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+pub fn write_record<W: Write>(w: &mut W, kind: RecordKind, payload: &[u8]) -> io::Result<()> {
+    let len: u32 = payload
+        .len()
+        .try_into()
+        .ok()
+        .filter(|len| *len <= MAX_RECORD_LEN)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "record payload too large"))?;
+    let kind: u8 = kind.into();
+    let len = len.to_le_bytes();
+
+    w.write_all(&[kind])?;
+    w.write_all(&len)?;
+    w.write_all(&checksum(kind, &len, payload).to_le_bytes())?;
+    w.write_all(payload)
+}
+
+/// Reads the next record from a file of the given format version.
+/// Returns `None` if the end of the file is reached at a record
+/// boundary.
+///
+/// A record that is cut short yields an error of kind
+/// [io::ErrorKind::UnexpectedEof], and a record that fails its checksum
+/// an error of kind [io::ErrorKind::InvalidData].
+pub fn read_record<R: Read + ?Sized>(
+    r: &mut R,
+    version: u16,
+) -> io::Result<Option<(RecordKind, Vec<u8>)>> {
+    let mut kind = [0; 1];
+    if r.read(&mut kind)? == 0 {
+        return Ok(None);
+    }
+
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+    let mut crc = [0; 4];
+    if version >= 2 {
+        r.read_exact(&mut crc)?;
+    }
+
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupt record");
+    if u32::from_le_bytes(len) > MAX_RECORD_LEN {
+        return Err(corrupt());
+    }
+    let mut payload = vec![0; u32::from_le_bytes(len) as usize];
+    r.read_exact(&mut payload)?;
+
+    if version >= 2 && checksum(kind[0], &len, &payload) != u32::from_le_bytes(crc) {
+        return Err(corrupt());
+    }
+
+    Ok(Some((kind[0].into(), payload)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(kind: RecordKind, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![];
+        write_record(&mut buf, kind, payload).unwrap();
+        buf
+    }
+
+    #[test]
+    fn roundtrip() {
+        let buf = [
+            record(RecordKind::Packets, b"abc"),
+            record(RecordKind::Unknown(7), b""),
+        ]
+        .concat();
+        let mut r = &buf[..];
+
+        assert_eq!(
+            read_record(&mut r, FORMAT_VERSION).unwrap(),
+            Some((RecordKind::Packets, b"abc".to_vec()))
+        );
+        assert_eq!(
+            read_record(&mut r, FORMAT_VERSION).unwrap(),
+            Some((RecordKind::Unknown(7), vec![]))
+        );
+        assert_eq!(read_record(&mut r, FORMAT_VERSION).unwrap(), None);
+    }
+
+    #[test]
+    fn version_1_has_no_checksum() {
+        let buf = [&[1u8][..], &3u32.to_le_bytes(), b"abc"].concat();
+
+        assert_eq!(
+            read_record(&mut &buf[..], 1).unwrap(),
+            Some((RecordKind::Packets, b"abc".to_vec()))
+        );
+    }
+
+    #[test]
+    fn truncated_record() {
+        let buf = record(RecordKind::Packets, b"abc");
+
+        for len in 1..buf.len() {
+            let err = read_record(&mut &buf[..len], FORMAT_VERSION).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "cut at {}", len);
+        }
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut buf = record(RecordKind::Packets, b"abc");
+        *buf.last_mut().unwrap() ^= 1;
+
+        let err = read_record(&mut &buf[..], FORMAT_VERSION).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn oversized_length() {
+        let mut buf = record(RecordKind::Packets, b"abc");
+        buf[1..5].copy_from_slice(&(MAX_RECORD_LEN + 1).to_le_bytes());
+
+        let err = read_record(&mut &buf[..], FORMAT_VERSION).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
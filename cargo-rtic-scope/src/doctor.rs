@@ -0,0 +1,376 @@
+//! Validation of the tracing setup: the manifest, the configuration of
+//! the tracing peripherals of the target, and the trace stream itself.
+use crate::build::CargoWrapper;
+use crate::diag::{self, DiagnosableError};
+use crate::log;
+use crate::manifest::ManifestProperties;
+use crate::recovery::{Metadata, RecoveryError};
+use crate::setup::{self, reg, SetupError};
+use crate::sources::{self, baud, tty::BAUD_RATE_TOLERANCE};
+use crate::{DoctorOptions, RTICScopeError};
+
+use std::time::Instant;
+
+use anyhow::Context;
+use cargo_metadata::Artifact;
+use itm_decode::{Decoder, DecoderOptions};
+use probe_rs::{architecture::arm::SwoConfig, Core, MemoryInterface, Session};
+use rtic_scope_api::EventType;
+use thiserror::Error;
+
+/// Largest share of malformed packets in a healthy trace stream.
+const MALFORMED_TOLERANCE: f64 = 0.01;
+
+/// Hint for problems resolved by the setup functions of
+/// cortex-m-rtic-trace.
+const CORE_SETUP_HINT: &str = "Call cortex_m_rtic_trace::setup::core_peripherals in #[init], or pass --host-setup to `cargo rtic-scope trace`.";
+
+/// Hint for problems with registers that are read back from a target
+/// that may not have been configured yet.
+const RUNNING_HINT: &str = "The registers are read from the running target: ensure that it has executed #[init] since it was last reset.";
+
+/// A problem with the tracing setup.
+#[derive(Debug, Error)]
+pub enum Finding {
+    #[error("dwt_enter_id and dwt_exit_id both assign DWT comparator {0}")]
+    SharedComparator(usize),
+    #[error("A baud rate of {baud} cannot be derived from a trace clock of {freq} Hz")]
+    InvalidBaud { freq: u32, baud: u32 },
+    #[error("A baud rate of {baud} cannot be derived from a trace clock of {freq} Hz; the closest rate is {closest}")]
+    UnreachableBaud { freq: u32, baud: u32, closest: u32 },
+    #[error("Tracing is disabled: DEMCR.TRCENA is cleared")]
+    TraceDisabled,
+    #[error("The TPIU outputs {0} instead of asynchronous SWO (NRZ)")]
+    WrongPinProtocol(&'static str),
+    #[error("The TPIU outputs {actual} baud, but tpiu_baud is {configured}")]
+    BaudMismatch { actual: u32, configured: u32 },
+    #[error("The TPIU formatter is enabled")]
+    FormatterEnabled,
+    #[error("The ITM is disabled: ITM_TCR.ITMENA is cleared")]
+    ItmDisabled,
+    #[error("The ITM does not forward DWT packets: ITM_TCR.TXENA is cleared")]
+    DwtNotForwarded,
+    #[error("Exception tracing is disabled: DWT_CTRL.EXCTRCENA is cleared")]
+    ExceptionTraceDisabled,
+    #[error("DWT comparator {0} is assigned to software task tracing, but the target only has {1} comparators")]
+    NotEnoughComparators(usize, usize),
+    #[error("DWT comparator {0} is not configured")]
+    ComparatorUnused(usize),
+    #[error("DWT comparator {id} is in use by something else (COMP = {comp:#010x}, FUNCTION = {function:#x})")]
+    ComparatorBusy { id: usize, comp: u32, function: u32 },
+    #[error("The trace pins of the device are disabled (DBGMCU_CR = {0:#010x})")]
+    TracePinsDisabled(u32),
+    #[error("No trace data was received within {0} ms")]
+    NoTraceData(u128),
+    #[error("{malformed} of {total} sampled trace packets are malformed")]
+    MalformedTraceData { malformed: usize, total: usize },
+    #[error("{unmappable} of {total} sampled task events could not be mapped to RTIC tasks")]
+    UnmappableEvents { unmappable: usize, total: usize },
+}
+
+impl diag::DiagnosableError for Finding {
+    fn diagnose(&self) -> Vec<String> {
+        match self {
+            Self::SharedComparator(_) => vec![
+                "Assign distinct comparators to dwt_enter_id and dwt_exit_id in [package.metadata.rtic-scope].".to_string(),
+            ],
+            Self::InvalidBaud { .. } => vec![
+                "tpiu_baud in [package.metadata.rtic-scope] must be a non-zero rate no higher than tpiu_freq, the frequency of the trace clock.".to_string(),
+            ],
+            Self::UnreachableBaud { .. } => vec![
+                "The TPIU divides the trace clock by an integer prescaler. Choose a tpiu_baud that evenly divides tpiu_freq.".to_string(),
+            ],
+            Self::TraceDisabled
+            | Self::WrongPinProtocol(_)
+            | Self::ItmDisabled
+            | Self::DwtNotForwarded
+            | Self::ExceptionTraceDisabled => {
+                vec![CORE_SETUP_HINT.to_string(), RUNNING_HINT.to_string()]
+            }
+            Self::BaudMismatch { actual, .. } => vec![
+                format!("Set tpiu_baud to {} in [package.metadata.rtic-scope], or configure the TPIU of the target for the intended rate.", actual),
+                "tpiu_freq must be the frequency of the trace clock, usually the core clock.".to_string(),
+            ],
+            Self::FormatterEnabled => vec![
+                "Pass --tpiu-source-id 1 to `cargo rtic-scope trace` to decode the formatted trace stream.".to_string(),
+            ],
+            Self::NotEnoughComparators(_, n) => vec![
+                format!("Set dwt_enter_id and dwt_exit_id in [package.metadata.rtic-scope] to distinct comparators below {}.", n),
+            ],
+            Self::ComparatorUnused(_) => vec![
+                "Call cortex_m_rtic_trace::setup::assign_dwt_units in #[init], or pass --host-setup to `cargo rtic-scope trace`.".to_string(),
+                RUNNING_HINT.to_string(),
+            ],
+            Self::ComparatorBusy { .. } => vec![
+                "The comparator may be in use by a debugger watchpoint, or by the application itself. Assign free comparators via dwt_enter_id and dwt_exit_id.".to_string(),
+            ],
+            Self::TracePinsDisabled(_) => vec![
+                "Call cortex_m_rtic_trace::setup::device_peripherals in #[init], or pass --host-setup to `cargo rtic-scope trace`.".to_string(),
+                RUNNING_HINT.to_string(),
+            ],
+            Self::NoTraceData(_) => vec![
+                "Ensure that the SWO pin of the target is connected to the probe or serial adapter.".to_string(),
+                "Trace data is only emitted while tasks run. Ensure that the target is not halted.".to_string(),
+            ],
+            Self::MalformedTraceData { .. } => vec![
+                "Malformed packets usually result from a baud rate mismatch. `cargo rtic-scope trace --serial <device> --detect-baud` detects the baud rate of the target.".to_string(),
+            ],
+            Self::UnmappableEvents { .. } => vec![
+                "Ensure that pac_name, pac_version, pac_features, and interrupt_path name the PAC that the application is built against.".to_string(),
+            ],
+        }
+    }
+}
+
+/// Checks the tracing setup of the given application, printing each
+/// problem found along with hints on how to resolve it.
+pub fn run(
+    opts: &DoctorOptions,
+    cargo: &CargoWrapper,
+    artifact: &Artifact,
+) -> Result<(), RTICScopeError> {
+    let mut findings = vec![];
+
+    // All further checks depend on the manifest.
+    let manip = ManifestProperties::new(cargo, Some(&opts.pac))?;
+    check_manifest(&manip, &mut findings);
+    log::status("Checked", "manifest".to_string());
+
+    let elf = artifact.executable.as_ref().unwrap();
+    let watch = match setup::watch_address(elf.as_std_path()) {
+        Ok(watch) => Some(watch),
+        // The application has no software tasks to trace.
+        Err(SetupError::NoWatchVariables(_)) => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut session = opts
+        .flash_options
+        .probe_options
+        .simple_attach()
+        .context("Failed to attach to target session")?;
+    {
+        let mut core = session.core(0).map_err(SetupError::ProbeError)?;
+        check_registers(&mut core, &manip, watch, &mut findings).map_err(SetupError::ProbeError)?;
+    }
+    log::status(
+        "Checked",
+        format!(
+            "{} registers",
+            if setup::has_stm32_dbgmcu(&manip) {
+                "TPIU, ITM, DWT, and DBGMCU"
+            } else {
+                "TPIU, ITM, and DWT"
+            }
+        ),
+    );
+
+    // The trace stream cannot be sampled at a baud rate that is itself
+    // a finding. Should the sampled stream not be checkable, the
+    // findings made so far are still reported.
+    let mut stream = Ok(());
+    if setup::prescaler(manip.tpiu_freq, manip.tpiu_baud).is_ok() {
+        let bytes = match &opts.serial {
+            Some(dev) => baud::sample(dev, manip.tpiu_baud)?,
+            None => {
+                sample_probe(&mut session, &manip).map_err(sources::SourceError::SetupProbeError)?
+            }
+        };
+        let maps = crate::resolve_maps(cargo, &opts.pac, artifact)?;
+        let metadata = Metadata::new(
+            maps,
+            manip.clone(),
+            chrono::Local::now(),
+            manip.tpiu_freq,
+            None,
+        );
+        stream = check_stream(&bytes, &metadata, &mut findings);
+        log::status("Sampled", format!("{} bytes of trace data", bytes.len()));
+    }
+
+    if findings.is_empty() {
+        stream?;
+        log::status(
+            "Healthy",
+            "no problems found with the tracing setup".to_string(),
+        );
+        return Ok(());
+    }
+
+    for finding in &findings {
+        log::err(finding.to_string());
+        for hint in finding.diagnose() {
+            log::hint(hint);
+        }
+    }
+    stream?;
+    Err(RTICScopeError::DoctorFindings(findings.len()))
+}
+
+/// Whether the `actual` baud rate is close enough to the `wanted` one for
+/// UART framing to succeed.
+fn within_tolerance(actual: u32, wanted: u32) -> bool {
+    (actual as f64 - wanted as f64).abs() <= wanted as f64 * BAUD_RATE_TOLERANCE
+}
+
+fn check_manifest(manip: &ManifestProperties, findings: &mut Vec<Finding>) {
+    if manip.dwt_enter_id == manip.dwt_exit_id {
+        findings.push(Finding::SharedComparator(manip.dwt_enter_id));
+    }
+
+    let prescaler = match setup::prescaler(manip.tpiu_freq, manip.tpiu_baud) {
+        Ok(acpr) => acpr + 1,
+        Err(_) => {
+            findings.push(Finding::InvalidBaud {
+                freq: manip.tpiu_freq,
+                baud: manip.tpiu_baud,
+            });
+            return;
+        }
+    };
+    let closest = manip.tpiu_freq / prescaler;
+    if !within_tolerance(closest, manip.tpiu_baud) {
+        findings.push(Finding::UnreachableBaud {
+            freq: manip.tpiu_freq,
+            baud: manip.tpiu_baud,
+            closest,
+        });
+    }
+}
+
+fn check_registers(
+    core: &mut Core,
+    manip: &ManifestProperties,
+    watch: Option<u32>,
+    findings: &mut Vec<Finding>,
+) -> Result<(), probe_rs::Error> {
+    if core.read_word_32(reg::DEMCR)? & reg::DEMCR_TRCENA == 0 {
+        findings.push(Finding::TraceDisabled);
+    }
+
+    // TPIU
+    match core.read_word_32(reg::TPIU_SPPR)? & 0b11 {
+        reg::TPIU_SPPR_NRZ => (),
+        reg::TPIU_SPPR_PARALLEL => {
+            findings.push(Finding::WrongPinProtocol("parallel trace port data"))
+        }
+        reg::TPIU_SPPR_MANCHESTER => {
+            findings.push(Finding::WrongPinProtocol("asynchronous SWO (Manchester)"))
+        }
+        0b11 => findings.push(Finding::WrongPinProtocol("a reserved pin protocol")),
+        _ => unreachable!("SPPR.TXMODE is two bits wide"),
+    }
+    let actual = manip.tpiu_freq / (core.read_word_32(reg::TPIU_ACPR)? + 1);
+    if !within_tolerance(actual, manip.tpiu_baud) {
+        findings.push(Finding::BaudMismatch {
+            actual,
+            configured: manip.tpiu_baud,
+        });
+    }
+    if core.read_word_32(reg::TPIU_FFCR)? & reg::TPIU_FFCR_ENFCONT != 0 {
+        findings.push(Finding::FormatterEnabled);
+    }
+
+    // ITM
+    let tcr = core.read_word_32(reg::ITM_TCR)?;
+    if tcr & reg::ITM_TCR_ITMENA == 0 {
+        findings.push(Finding::ItmDisabled);
+    }
+    if tcr & reg::ITM_TCR_TXENA == 0 {
+        findings.push(Finding::DwtNotForwarded);
+    }
+
+    // DWT
+    let ctrl = core.read_word_32(reg::DWT_CTRL)?;
+    if ctrl & reg::DWT_CTRL_EXCTRCENA == 0 {
+        findings.push(Finding::ExceptionTraceDisabled);
+    }
+    if let Some(watch) = watch {
+        let comparators = (ctrl >> reg::DWT_CTRL_NUMCOMP_SHIFT) as usize;
+        for (id, addr) in [(manip.dwt_enter_id, watch), (manip.dwt_exit_id, watch + 4)] {
+            if id >= comparators {
+                findings.push(Finding::NotEnoughComparators(id, comparators));
+                continue;
+            }
+
+            let comp = core.read_word_32(reg::dwt_comp(id))?;
+            let function = core.read_word_32(reg::dwt_function(id))? & 0xf;
+            if function == 0 {
+                findings.push(Finding::ComparatorUnused(id));
+            } else if function != reg::DWT_FUNCTION_DATA_WRITE || comp != addr {
+                findings.push(Finding::ComparatorBusy { id, comp, function });
+            }
+        }
+    }
+
+    // Debug MCU
+    if setup::has_stm32_dbgmcu(manip) {
+        let cr = core.read_word_32(reg::STM32_DBGMCU_CR)?;
+        if cr & reg::STM32_DBGMCU_CR_TRACE_IOEN == 0 || cr & reg::STM32_DBGMCU_CR_TRACE_MODE != 0 {
+            findings.push(Finding::TracePinsDisabled(cr));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the trace stream from the probe for [baud::SAMPLE_WINDOW].
+fn sample_probe(
+    session: &mut Session,
+    manip: &ManifestProperties,
+) -> Result<Vec<u8>, probe_rs::Error> {
+    let cfg = SwoConfig::new(manip.tpiu_freq)
+        .set_baud(manip.tpiu_baud)
+        .set_continuous_formatting(false);
+    session.setup_swv(0, &cfg)?;
+
+    let mut bytes = vec![];
+    let deadline = Instant::now() + baud::SAMPLE_WINDOW;
+    while Instant::now() < deadline {
+        bytes.extend(session.read_swo()?);
+    }
+
+    Ok(bytes)
+}
+
+/// Checks the decoded trace stream. Fails if the packets cannot be
+/// mapped to events at all.
+fn check_stream(
+    bytes: &[u8],
+    metadata: &Metadata,
+    findings: &mut Vec<Finding>,
+) -> Result<(), RecoveryError> {
+    if bytes.is_empty() {
+        findings.push(Finding::NoTraceData(baud::SAMPLE_WINDOW.as_millis()));
+        return Ok(());
+    }
+
+    let (mut packets, mut malformed, mut tasks, mut unmappable) = (0, 0, 0, 0);
+    let mut decoder = Decoder::new(DecoderOptions::default());
+    decoder.push(bytes);
+    while let Some(data) = decoder.pull_with_timestamp() {
+        packets += data.packets.len();
+        malformed += data.malformed_packets.len();
+
+        for event in metadata.try_build_event_chunk(&data)?.events {
+            match event {
+                EventType::Task { .. } => tasks += 1,
+                EventType::Unmappable(..) => unmappable += 1,
+                _ => (),
+            }
+        }
+    }
+
+    let total = packets + malformed;
+    if malformed as f64 > total as f64 * MALFORMED_TOLERANCE {
+        findings.push(Finding::MalformedTraceData { malformed, total });
+    }
+    if unmappable > 0 {
+        findings.push(Finding::UnmappableEvents {
+            unmappable,
+            total: tasks + unmappable,
+        });
+    }
+
+    Ok(())
+}
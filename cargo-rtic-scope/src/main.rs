@@ -1,12 +1,13 @@
 use std::env;
 use std::fs;
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Arc,
+    mpsc, Arc,
 };
+use std::thread;
 
 use anyhow::{anyhow, Context};
 use cargo_metadata::Artifact;
@@ -14,17 +15,24 @@ use probe_rs_cli_util::{
     common_options::{CargoOptions, FlashOptions},
     flash,
 };
-use rtic_scope_api as api;
+use serde::Serialize;
 use structopt::StructOpt;
 use thiserror::Error;
 
+mod analysis;
+mod attach;
 mod build;
+mod container;
 mod diag;
+mod doctor;
 mod log;
 mod manifest;
 mod recovery;
+mod setup;
 mod sinks;
 mod sources;
+#[cfg(test)]
+mod throughput;
 
 use build::CargoWrapper;
 
@@ -36,6 +44,11 @@ struct Opts {
     #[structopt(long = "frontend", short = "-F", default_value = "dummy")]
     frontends: Vec<String>,
 
+    /// Do not forward the trace to any frontend, e.g. when only
+    /// recording or exporting it.
+    #[structopt(long = "no-frontend")]
+    no_frontend: bool,
+
     #[structopt(subcommand)]
     cmd: Command,
 }
@@ -62,6 +75,78 @@ struct TraceOptions {
     #[structopt(long = "clear-traces")]
     remove_prev_traces: bool,
 
+    /// Compress the recorded trace stream with zstd.
+    #[structopt(long = "compress")]
+    compress: bool,
+
+    /// Also record the raw trace stream, so that it can be decoded
+    /// anew during replay.
+    #[structopt(name = "record-raw", long = "record-raw")]
+    record_raw: bool,
+
+    /// Read the raw trace stream from the given named pipe, or from
+    /// stdin if "-", as captured by an external tool. The target is
+    /// neither flashed nor reset.
+    #[structopt(
+        name = "raw-input",
+        long = "raw-input",
+        parse(from_os_str),
+        conflicts_with = "serial"
+    )]
+    raw_input: Option<PathBuf>,
+
+    /// Read the raw trace stream from a server at the given host:port,
+    /// such as the SWO port of an OpenOCD instance. The target is
+    /// neither flashed nor reset. The connection is reestablished if
+    /// lost. To replay a raw capture over TCP, serve it with, e.g.,
+    /// `nc -l 4444 < capture.bin`.
+    #[structopt(name = "tcp", long = "tcp", conflicts_with_all(&["serial", "raw-input"]))]
+    tcp: Option<String>,
+
+    /// Detect the SWO baud rate by sampling the trace stream on
+    /// --serial at a number of candidate rates, and trace at the most
+    /// plausible rate instead of the configured one. The target is
+    /// reset before sampling.
+    #[structopt(name = "detect-baud", long = "detect-baud", requires("serial"))]
+    detect_baud: bool,
+
+    /// Comma-separated baud rates sampled by --detect-baud. Defaults to
+    /// common SWO rates and the configured rate.
+    #[structopt(
+        long = "baud-candidates",
+        use_delimiter = true,
+        requires("detect-baud")
+    )]
+    baud_candidates: Vec<u32>,
+
+    /// Trace the running target without flashing or resetting it. The
+    /// firmware on the target is verified to match the built
+    /// application. As the moment of the last reset is unknown,
+    /// timestamps are relative to the start of the trace.
+    #[structopt(
+        name = "attach",
+        long = "attach",
+        conflicts_with_all(&["raw-input", "tcp", "detect-baud"])
+    )]
+    attach: bool,
+
+    /// Configure the tracing peripherals of the target (DCB, TPIU, DWT,
+    /// and ITM, and on STM32 devices the trace pins) through the probe,
+    /// for applications that do not call the setup functions of
+    /// cortex-m-rtic-trace.
+    #[structopt(long = "host-setup", conflicts_with_all(&["raw-input", "tcp"]))]
+    host_setup: bool,
+
+    /// End the session with an error if no trace data is decoded for
+    /// the given number of seconds, instead of waiting indefinitely on
+    /// a stalled trace stream.
+    #[structopt(
+        long = "stall-timeout",
+        parse(try_from_str = parse_secs),
+        conflicts_with_all(&["raw-input", "tcp"])
+    )]
+    stall_timeout: Option<std::time::Duration>,
+
     /// Only resolve the translation maps; do not program or trace the target.
     #[structopt(long = "resolve-only")]
     resolve_only: bool,
@@ -69,10 +154,74 @@ struct TraceOptions {
     #[structopt(flatten)]
     pac: ManifestOptions,
 
+    #[structopt(flatten)]
+    statistics: StatisticsOptions,
+
+    #[structopt(flatten)]
+    tpiu: TpiuOptions,
+
+    #[structopt(flatten)]
+    limits: CaptureLimits,
+
+    #[structopt(flatten)]
+    trigger: TriggerOptions,
+
     #[structopt(flatten)]
     flash_options: FlashOptions,
 }
 
+/// Conditions on which a trace session is ended before the source is
+/// exhausted or SIGINT is received.
+#[derive(StructOpt, Debug)]
+struct CaptureLimits {
+    /// End the session after the given number of seconds.
+    #[structopt(long = "duration", parse(try_from_str = parse_secs))]
+    duration: Option<std::time::Duration>,
+
+    /// End the session after the given number of ITM packets have been
+    /// received.
+    #[structopt(long = "max-packets")]
+    max_packets: Option<usize>,
+
+    /// End the session once the given task has been entered. The task
+    /// is named either in full (e.g. app::blink) or by its function
+    /// name only (e.g. blink).
+    #[structopt(name = "stop-on-task", long = "stop-on-task")]
+    stop_on_task: Option<String>,
+
+    /// Number of times the task given by --stop-on-task must be entered
+    /// before the session is ended. Defaults to 1.
+    #[structopt(long = "stop-on-task-count", requires("stop-on-task"))]
+    stop_on_task_count: Option<usize>,
+}
+
+#[derive(StructOpt, Debug)]
+struct TriggerOptions {
+    /// Only record the trace stream around the moments the given
+    /// condition occurs: task-entered=<task>, overrun (of a timing
+    /// constraint), overflow, or hardfault. May be given multiple
+    /// times. Cannot be combined with --record-raw, as the recorded
+    /// captures are not contiguous.
+    #[structopt(long = "trigger", number_of_values = 1, conflicts_with = "record-raw")]
+    triggers: Vec<sinks::Trigger>,
+
+    /// Number of packet groups preceding a trigger to record.
+    #[structopt(long = "pre-trigger", default_value = "1000")]
+    pre: usize,
+
+    /// Number of packet groups following a trigger to record.
+    #[structopt(long = "post-trigger", default_value = "1000")]
+    post: usize,
+}
+
+fn parse_secs(s: &str) -> Result<std::time::Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(std::time::Duration::from_secs_f64)
+        .ok_or_else(|| format!("{} is not a valid number of seconds", s))
+}
+
 #[derive(StructOpt, Debug)]
 pub struct ManifestOptions {
     /// Name of the PAC used in traced application.
@@ -93,7 +242,7 @@ pub struct ManifestOptions {
 
     /// Speed in Hz of the TPIU trace clock. Used to calculate
     /// timestamps of received timestamps.
-    #[structopt(long = "tpiu-freq")]
+    #[structopt(long = "tpiu-freq", name = "tpiu-freq")]
     tpiu_freq: Option<u32>,
 
     /// Baud rate of the communication from the target TPIU.
@@ -101,18 +250,74 @@ pub struct ManifestOptions {
     tpiu_baud: Option<u32>,
 }
 
+/// Check the manifest and the tracing setup of the target, and diagnose
+/// common reasons for an empty or garbled trace stream. The target is
+/// neither flashed nor reset, and should run the built application.
+#[derive(StructOpt, Debug)]
+struct DoctorOptions {
+    /// Optional serial device over which trace stream is expected,
+    /// instead of a CMSIS-DAP device.
+    #[structopt(long = "serial")]
+    serial: Option<String>,
+
+    #[structopt(flatten)]
+    pac: ManifestOptions,
+
+    #[structopt(flatten)]
+    flash_options: FlashOptions,
+}
+
 /// Replay a previously recorded trace stream for post-mortem analysis.
 #[derive(StructOpt, Debug)]
 struct ReplayOptions {
+    /// List the traces in <trace-dir> along with a summary of each.
     #[structopt(name = "list", long = "list", short = "l")]
     list: bool,
 
-    #[structopt(required_unless_one(&["list", "raw-file"]))]
-    index: Option<usize>,
+    /// Output format of --list.
+    #[structopt(long = "format", default_value = "text", possible_values(&["text", "json"]))]
+    format: OutputFormat,
+
+    /// The trace to replay: an index as printed by --list, a path to a
+    /// trace file, or a glob pattern matched against the file names of
+    /// the traces in <trace-dir>.
+    #[structopt(
+        name = "trace",
+        required_unless_one(&["list", "raw-file", "raw-input", "trace-file", "latest"])
+    )]
+    trace: Option<TraceSelector>,
+
+    /// Replay the most recent trace in <trace-dir>, or the most recent
+    /// trace matching <trace> if it is a glob pattern.
+    #[structopt(name = "latest", long = "latest", conflicts_with_all(&["list", "raw-file", "raw-input"]))]
+    latest: bool,
+
+    /// Path to a trace file to replay. The target application is not
+    /// built, so this works outside of the application's cargo
+    /// project.
+    #[structopt(
+        name = "trace-file",
+        long = "trace-file",
+        parse(from_os_str),
+        conflicts_with_all(&["list", "raw-file", "raw-input"])
+    )]
+    trace_file: Option<PathBuf>,
+
+    /// Decode the recorded raw trace stream with the current decoder
+    /// instead of replaying the recorded trace packets. Requires that
+    /// the trace was recorded with --record-raw.
+    #[structopt(long = "redecode")]
+    redecode: bool,
 
     #[structopt(flatten)]
     raw_options: RawFileOptions,
 
+    #[structopt(flatten)]
+    export: ExportOptions,
+
+    #[structopt(flatten)]
+    statistics: StatisticsOptions,
+
     /// Directory where previously recorded trace streams. By default,
     /// the build cache of <bin> is used (usually ./target/).
     #[structopt(name = "trace-dir", long = "trace-dir", parse(from_os_str))]
@@ -122,23 +327,146 @@ struct ReplayOptions {
     cargo_options: CargoOptions,
 }
 
+impl ReplayOptions {
+    /// Returns the path of the trace file to replay if it was given
+    /// explicitly.
+    fn trace_path(&self) -> Option<&Path> {
+        match (&self.trace_file, &self.trace) {
+            (Some(path), _) | (None, Some(TraceSelector::Path(path))) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown output format {}", s)),
+        }
+    }
+}
+
+/// Selects a trace to replay.
+#[derive(Debug)]
+enum TraceSelector {
+    /// Index into the traces found in the trace directory, sorted by
+    /// their reset timestamps.
+    Index(usize),
+    Path(PathBuf),
+    /// Pattern matched against the file names of the traces found in
+    /// the trace directory.
+    Glob(glob::Pattern),
+}
+
+impl std::str::FromStr for TraceSelector {
+    type Err = glob::PatternError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(idx) = s.parse() {
+            Ok(Self::Index(idx))
+        } else if Path::new(s).is_file() {
+            Ok(Self::Path(s.into()))
+        } else {
+            Ok(Self::Glob(glob::Pattern::new(s)?))
+        }
+    }
+}
+
 #[derive(StructOpt, Debug)]
 struct RawFileOptions {
     /// Path to the file containing raw trace data that should be
-    /// replayed.
-    #[structopt(name = "raw-file", long = "raw-file", requires("virtual-freq"))]
+    /// replayed. Timestamps are computed from the TPIU frequency given
+    /// by --tpiu-freq, or else by `tpiu_freq` in the manifest.
+    #[structopt(name = "raw-file", long = "raw-file")]
     file: Option<PathBuf>,
 
+    /// Path to a named pipe over which raw trace data is streamed, or
+    /// "-" to read it from stdin.
+    #[structopt(
+        name = "raw-input",
+        long = "raw-input",
+        parse(from_os_str),
+        conflicts_with = "raw-file"
+    )]
+    input: Option<PathBuf>,
+
     #[structopt(long = "comment", short = "c", hidden = true)]
     comment: Option<String>,
     #[structopt(flatten)]
     pac: ManifestOptions,
+    #[structopt(flatten)]
+    tpiu: TpiuOptions,
+}
+
+/// Options for raw trace streams in which the TPIU formatter
+/// multiplexes the data of several trace sources, e.g. the ITM and the
+/// ETM.
+#[derive(StructOpt, Debug)]
+struct TpiuOptions {
+    /// Strip the TPIU formatter frames from the raw trace stream,
+    /// decoding the data of the trace source with the given ID: the
+    /// ATB ID of the ITM, which cortex-m-rtic-trace sets to 1. The
+    /// formatter must be enabled on the target.
+    #[structopt(name = "tpiu-source-id", long = "tpiu-source-id")]
+    source_id: Option<u8>,
+
+    /// Write the data of all other trace sources to
+    /// <dir>/tpiu-source-<id>.bin instead of discarding it.
+    #[structopt(
+        long = "tpiu-others-dir",
+        parse(from_os_str),
+        requires("tpiu-source-id")
+    )]
+    others_dir: Option<PathBuf>,
+}
+
+impl TpiuOptions {
+    fn demux(&self) -> Option<sources::TpiuDemux> {
+        self.source_id
+            .map(|id| sources::TpiuDemux::new(id, self.others_dir.clone()))
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct ExportOptions {
+    /// Export the replayed trace to the given file in the Trace Event
+    /// Format, for use with Perfetto or chrome://tracing.
+    #[structopt(long = "export-chrome", parse(from_os_str))]
+    chrome: Option<PathBuf>,
+
+    /// Export the replayed trace to the given file as a Value Change
+    /// Dump, for use with GTKWave.
+    #[structopt(long = "export-vcd", parse(from_os_str))]
+    vcd: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+struct StatisticsOptions {
+    /// Output format of the per-task timing statistics printed when the
+    /// session ends.
+    #[structopt(
+        long = "stats-format",
+        default_value = "text",
+        possible_values(&["text", "json"])
+    )]
+    format: OutputFormat,
 }
 
 #[derive(StructOpt, Debug)]
 enum Command {
     Trace(TraceOptions),
     Replay(ReplayOptions),
+    Doctor(DoctorOptions),
 }
 
 #[derive(Debug, Error)]
@@ -148,6 +476,10 @@ pub enum RTICScopeError {
     CommonProbeOperationError(#[from] probe_rs_cli_util::common_options::OperationError),
     #[error("I/O operation failed: {0}")]
     IOError(#[from] std::io::Error),
+    #[error("{0} timing constraint violation(s) detected")]
+    TimingViolations(usize),
+    #[error("{0} problem(s) found with the tracing setup")]
+    DoctorFindings(usize),
 
     // transparent errors
     #[error(transparent)]
@@ -159,6 +491,10 @@ pub enum RTICScopeError {
     #[error(transparent)]
     SourceError(#[from] sources::SourceError),
     #[error(transparent)]
+    AttachError(#[from] attach::AttachError),
+    #[error(transparent)]
+    SetupError(#[from] setup::SetupError),
+    #[error(transparent)]
     SinkError(#[from] sinks::SinkError),
 
     // everything else
@@ -172,6 +508,9 @@ impl diag::DiagnosableError for RTICScopeError {
             RTICScopeError::ManifestError(_) => vec![
                 "[package.metadata.rtic-scope] takes precedence over [workspace.metadata.rtic-scope]".to_string(),
             ],
+            RTICScopeError::TimingViolations(_) => vec![
+                "Timing constraints are declared under [package.metadata.rtic-scope.constraints] in Cargo.toml and recorded alongside the trace".to_string(),
+            ],
             _ => vec![],
         }
     }
@@ -191,6 +530,8 @@ impl RTICScopeError {
                 Self::MetadataError(e) => Some(e as &DE),
                 Self::CargoError(e) => Some(e as &DE),
                 Self::SourceError(e) => Some(e as &DE),
+                Self::AttachError(e) => Some(e as &DE),
+                Self::SetupError(e) => Some(e as &DE),
                 Self::SinkError(e) => Some(e as &DE),
                 _ => None,
             }
@@ -223,62 +564,76 @@ fn main_try() -> Result<(), RTICScopeError> {
     let opts = Opts::from_clap(&matches);
 
     // Should we quit early?
-    if let Command::Trace(opts) = &opts.cmd {
-        let fo = &opts.flash_options;
+    if let Command::Trace(TraceOptions {
+        flash_options: fo, ..
+    })
+    | Command::Doctor(DoctorOptions {
+        flash_options: fo, ..
+    }) = &opts.cmd
+    {
         fo.probe_options.maybe_load_chip_desc()?;
         if fo.early_exit(std::io::stdout())? {
             return Ok(());
         }
     }
 
-    // Build RTIC application to be traced, and create a wrapper around
-    // cargo, reusing the target directory of the application.
-    log::status("Building", "RTIC target application...".to_string());
-    let (cargo, artifact) = CargoWrapper::new(
-        &env::current_dir()?,
-        {
-            match &opts.cmd {
-                Command::Trace(opts) => &opts.flash_options.cargo_options,
-                Command::Replay(opts) => &opts.cargo_options,
-            }
+    // Configure source and sinks. Recover the information we need to
+    // map IRQ numbers to RTIC tasks.
+    let ((source, mut sinks, metadata), prog) = match opts.cmd {
+        Command::Replay(ref replay_opts) if replay_opts.trace_path().is_some() => {
+            // The trace file header contains all metadata we need: the
+            // target application need not be built.
+            let file = replay_opts.trace_path().unwrap();
+            let src =
+                open_trace_file(file, replay_opts.redecode).context("Failed to replay trace")?;
+            let metadata = src.metadata();
+            let prog = match metadata.program() {
+                Some(prog) => prog.to_string(),
+                None => file
+                    .file_name()
+                    .map(|f| f.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            };
+            log::status("Replaying", format!("{}...", file.display()));
+
+            (
+                (Box::new(src) as Box<dyn sources::Source>, vec![], metadata),
+                prog,
+            )
         }
-        .to_cargo_options(),
-    )?;
-
-    let prog = format!("{} ({})", artifact.target.name, artifact.target.src_path,);
-    let preparing_target = match opts.cmd {
-        Command::Trace(ref opts) => !opts.resolve_only,
-        Command::Replay(_) => false,
-    };
-    log::status(
-        "Recovering",
-        format!(
-            "metadata for {}{}",
-            prog,
-            if preparing_target {
-                " and preparing target..."
+        Command::Doctor(ref doctor_opts) => {
+            let (cargo, artifact) = build(&doctor_opts.flash_options.cargo_options)?;
+            return doctor::run(doctor_opts, &cargo, &artifact);
+        }
+        Command::Trace(ref trace_opts) => {
+            let (cargo, artifact) = build(&trace_opts.flash_options.cargo_options)?;
+            let target = if trace_opts.resolve_only
+                || trace_opts.raw_input.is_some()
+                || trace_opts.tcp.is_some()
+            {
+                None
+            } else if trace_opts.attach {
+                Some("Attached to running target")
             } else {
-                "..."
+                Some("Target reset and flashed")
+            };
+            match prepare(&artifact, target, || trace(trace_opts, &cargo, &artifact))? {
+                Some(tup) => tup,
+                None => return Ok(()),
             }
-        ),
-    );
-
-    // Configure source and sinks. Recover the information we need to
-    // map IRQ numbers to RTIC tasks.
-    let (source, mut sinks, metadata) = match opts.cmd {
-        Command::Trace(ref opts) => match trace(opts, &cargo, &artifact)? {
-            Some(tup) => tup,
-            None => return Ok(()),
-        },
-        Command::Replay(ref opts) => {
-            match replay(opts, &cargo, &artifact).with_context(|| {
-                format!("Failed to {}", {
-                    if opts.list {
-                        "index traces"
-                    } else {
-                        "replay trace"
-                    }
-                })
+        }
+        Command::Replay(ref replay_opts) => {
+            let (cargo, artifact) = build(&replay_opts.cargo_options)?;
+            match prepare(&artifact, None, || {
+                Ok(replay(replay_opts, &cargo, &artifact).with_context(|| {
+                    format!("Failed to {}", {
+                        if replay_opts.list {
+                            "index traces"
+                        } else {
+                            "replay trace"
+                        }
+                    })
+                })?)
             })? {
                 Some(tup) => tup,
                 None => return Ok(()), // NOTE --list was passed
@@ -286,20 +641,19 @@ fn main_try() -> Result<(), RTICScopeError> {
         }
     };
 
-    log::status(
-        "Recovered",
-        format!(
-            "{ntotal} task(s) from {prog}: {nhard} hard, {nsoft} soft. Target reset and flashed.",
-            ntotal = metadata.hardware_tasks() + metadata.software_tasks(),
-            prog = artifact.target.name,
-            nhard = metadata.hardware_tasks(),
-            nsoft = metadata.software_tasks()
-        ),
-    );
+    // Create and push any export sinks.
+    if let Command::Replay(ref opts) = opts.cmd {
+        sinks.append(&mut export_sinks(&opts.export, &metadata)?);
+    }
 
     // Spawn frontend children and get path to sockets. Create and push sinks.
     let mut children = vec![];
-    for frontend in &opts.frontends {
+    let frontends: &[String] = if opts.no_frontend {
+        &[]
+    } else {
+        &opts.frontends
+    };
+    for frontend in frontends {
         let executable = format!("rtic-scope-frontend-{}", frontend);
         let mut child = process::Command::new(&executable)
             .stdout(process::Stdio::piped())
@@ -340,7 +694,7 @@ fn main_try() -> Result<(), RTICScopeError> {
 
     // All preparatory I/O and information recovery done. Forward all
     // trace packets to all sinks.
-    let retstatus = run_loop(source, sinks, metadata, &opts, artifact.target.name);
+    let retstatus = run_loop(source, sinks, metadata, &opts, prog);
 
     // Wait for frontends to proccess all packets and echo its' stderr
     for (i, (child, stderr)) in children.iter_mut().enumerate() {
@@ -364,8 +718,88 @@ fn main_try() -> Result<(), RTICScopeError> {
     retstatus
 }
 
-fn run_loop(
+/// Number of packet groups read from the source that may be queued for
+/// processing before reading from the source is paused.
+const SOURCE_QUEUE_LEN: usize = 4096;
+
+/// How often the status line is refreshed and capture limits are
+/// checked in the absence of trace data.
+const IDLE_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+type SourceMessage = anyhow::Result<(TraceData, Option<Vec<u8>>)>;
+
+/// Reads the source on a dedicated thread so that it is read as quickly
+/// as possible, regardless of how quickly the trace data is processed.
+/// Reading stops on the first error, when the source is exhausted, or
+/// when the returned receiver is dropped. Raw bytes read after the last
+/// packet group of an exhausted source are forwarded in an empty packet
+/// group.
+fn spawn_reader(
     mut source: Box<dyn sources::Source>,
+    halt: Arc<AtomicBool>,
+) -> mpsc::Receiver<SourceMessage> {
+    let (tx, rx) = mpsc::sync_channel(SOURCE_QUEUE_LEN);
+    source.halt_on(halt.clone());
+
+    thread::spawn(move || {
+        let mut buffer_warning = false;
+        let mut last_timestamp = None;
+
+        while let Some(data) = source.next() {
+            if halt.load(Ordering::SeqCst) {
+                return;
+            }
+
+            // Eventually warn about the source input buffer overflowing,
+            // but only once.
+            if !buffer_warning {
+                if let sources::BufferStatus::AvailWarn(avail, buf_sz) = source.avail_buffer() {
+                    eprintln!(
+                        "Source buffer is almost full ({}/{} bytes free) and it not read quickly enough",
+                        avail, buf_sz
+                    );
+                    buffer_warning = true;
+                }
+            }
+
+            if let Ok(data) = &data {
+                last_timestamp = Some(data.timestamp.clone());
+            }
+            let data = data.with_context(|| {
+                format!(
+                    "Failed to read trace data from source {}",
+                    source.describe()
+                )
+            });
+            let failed = data.is_err();
+
+            // Forward the raw bytes the packets were decoded from, if
+            // recorded.
+            let raw = source.take_raw();
+            if tx.send(data.map(|data| (data, raw))).is_err() || failed {
+                return;
+            }
+        }
+
+        if let Some(raw) = source.take_raw().filter(|raw| !raw.is_empty()) {
+            let data = TraceData {
+                timestamp: last_timestamp.unwrap_or(itm_decode::Timestamp {
+                    delta: Some(0),
+                    ..Default::default()
+                }),
+                packets: vec![],
+                malformed_packets: vec![],
+                packets_consumed: 0,
+            };
+            let _ = tx.send(Ok((data, Some(raw))));
+        }
+    });
+
+    rx
+}
+
+fn run_loop(
+    source: Box<dyn sources::Source>,
     mut sinks: Vec<Box<dyn sinks::Sink>>,
     metadata: recovery::Metadata,
     opts: &Opts,
@@ -380,116 +814,98 @@ fn run_loop(
     })
     .context("Failed to install SIGINT handler")?;
 
-    // Keep tabs on which sinks have broken during drain, if any.
-    let mut sinks: Vec<(Box<dyn sinks::Sink>, bool)> =
-        sinks.drain(..).map(|s| (s, false)).collect();
-    let sinks_at_start = sinks.len();
+    // Drain each sink on its own thread. While tracing, a frontend that
+    // cannot keep up drops packets rather than stalling the others;
+    // the recording sink is never lossy, and when replaying there is no
+    // need to.
+    let tracing = matches!(opts.cmd, Command::Trace(_));
+    let mut workers: Vec<sinks::SinkWorker> = sinks
+        .drain(..)
+        .map(|sink| {
+            let lossy = tracing && sink.lossy();
+            sinks::SinkWorker::spawn(sink, lossy, halt.clone())
+        })
+        .collect();
+    let sinks_at_start = workers.len();
 
-    let mut retstatus = Ok(());
-    let mut buffer_warning = false;
+    let source = spawn_reader(source, halt.clone());
 
-    #[derive(Default)]
-    struct Stats {
-        // How many ITM packets we have received from the source...
-        pub packets: usize,
+    let mut retstatus: Result<(), RTICScopeError> = Ok(());
 
-        // ...of which, how many are malformed/nonmappable?
-        pub malformed: usize,
-        pub nonmappable: usize,
-    }
-    let mut stats = Stats::default();
+    // How many ITM packets we have received from the source. How many of
+    // them are malformed/nonmappable is counted by the analysis.
+    let mut packets_received = 0;
+    let metadata = Arc::new(metadata);
+
+    let (limits, statistics) = match opts.cmd {
+        Command::Trace(ref opts) => (Some(&opts.limits), Some(&opts.statistics)),
+        Command::Replay(ref opts) => (None, Some(&opts.statistics)),
+        Command::Doctor(_) => (None, None),
+    };
+    let mut stop_reason = None;
+    let mut analyzer = analysis::SessionAnalysis::spawn(
+        metadata.constraints().clone(),
+        limits.and_then(|l| l.stop_on_task.clone()),
+        halt.clone(),
+    );
 
     let instant = std::time::Instant::now();
 
-    while let Some(data) = source.next() {
+    loop {
         if halt.load(Ordering::SeqCst) {
             break;
         }
 
-        // Eventually warn about the source input buffer overflowing,
-        // but only once.
-        if !buffer_warning {
-            if let sources::BufferStatus::AvailWarn(avail, buf_sz) = source.avail_buffer() {
-                eprintln!(
-                    "Source buffer is almost full ({}/{} bytes free) and it not read quickly enough",
-                    avail, buf_sz
-                );
-                buffer_warning = true;
-            }
-        }
+        match source.recv_timeout(IDLE_POLL_INTERVAL) {
+            Ok(Ok((data, raw))) => {
+                // The packets and their RTIC events, which are mapped
+                // once when first needed, are shared with the analysis
+                // and all sinks.
+                packets_received += data.packets_consumed;
+                let packets = Arc::new(sinks::Packets::new(data, raw, metadata.clone()));
+                analyzer.send(packets.clone());
+
+                let mut broken = vec![];
+                for (i, worker) in workers.iter_mut().enumerate() {
+                    if !worker.send(packets.clone()) {
+                        broken.push(i);
+                    }
+                }
 
-        // Try to read data from the source. Failure is fatal.
-        let data = data.with_context(|| {
-            format!(
-                "Failed to read trace data from source {}",
-                source.describe()
-            )
-        })?;
-
-        // Try to recover RTIC information for the packets.
-        let chunk = metadata.build_event_chunk(data.clone());
-
-        // Report any unmappable/unknown events that occured, and record stats
-        stats.packets += data.packets_consumed;
-        for event in chunk.events.iter() {
-            match event {
-                api::EventType::Unmappable(ref packet, ref reason) => {
-                    stats.nonmappable += 1;
-                    log::warn(format!(
-                        "cannot map {:?} packet: {}",
-                        packet, reason
-                    ));
+                // remove broken sinks, if any
+                for i in broken.into_iter().rev() {
+                    workers.remove(i).finish();
                 }
-                api::EventType::Unknown(ref packet) => {
-                    stats.nonmappable += 1;
-                    log::warn(format!(
-                        "cannot map {:?} packet",
-                        packet
-                    ));
+                if workers.is_empty() && sinks_at_start != 0 {
+                    retstatus = Err(anyhow!("All sinks broken. Cannot continue.").into());
+                    break;
                 }
-                api::EventType::Invalid(ref malformed) => {
-                    stats.malformed += 1;
-                    log::warn(format!("malformed packet: {}: {:?}", malformed, malformed));
-                },
-                api::EventType::Overflow => log::warn("Overflow detected! Packets may have been dropped and timestamps will be diverged until the next global timestamp".to_string()),
-                _ => (),
             }
-        }
-
-        for (sink, is_broken) in sinks.iter_mut() {
-            if let Err(e) = sink.drain(data.clone(), chunk.clone()) {
-                log::err(format!(
-                    "failed to drain trace packets to {}: {:?}",
-                    sink.describe(),
-                    e
-                ));
-                *is_broken = true;
+            // Failure to read from the source is fatal. Source errors
+            // are unwrapped, so that their hints are rendered.
+            Ok(Err(e)) => {
+                retstatus = Err(e
+                    .downcast::<sources::SourceError>()
+                    .map_or_else(RTICScopeError::from, RTICScopeError::from));
+                break;
             }
-        }
-
-        // remove broken sinks, if any
-        //
-        // TODO replace weth Vec::drain_filter when stable.
-        sinks.retain(|(_, is_broken)| !is_broken);
-        if sinks.is_empty() {
-            retstatus = Err(anyhow!("All sinks broken. Cannot continue."));
-            break;
+            // Refresh the status and check the limits regardless.
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            // The source is exhausted.
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
         }
 
         let duration = instant.elapsed();
         log::cont_status(
-            match opts.cmd {
-                Command::Trace(_) => "Tracing",
-                Command::Replay(_) => "Replaying",
-            },
+            if tracing { "Tracing" } else { "Replaying" },
             format!(
                 "{}: {} packets processed in {time} (~{packets_per_sec} packets/s; {} malformed, {} non-mappable); {sinks}...",
                 prog,
-                stats.packets,
-                stats.malformed,
-                stats.nonmappable,
+                packets_received,
+                analyzer.counters().malformed.load(Ordering::SeqCst),
+                analyzer.counters().nonmappable.load(Ordering::SeqCst),
                 packets_per_sec = if duration.as_secs() != 0 {
-                    stats.packets / duration.as_secs() as usize
+                    packets_received / duration.as_secs() as usize
                 } else {
                     0
                 },
@@ -513,15 +929,106 @@ fn run_loop(
                         format!("{}s", secs)
                     }
                 },
-                sinks = format!("{}/{} sinks operational", sinks.len(), sinks_at_start),
+                sinks = format!(
+                    "{}/{} sinks operational (queued/dropped/stalled: {})",
+                    workers.len(),
+                    sinks_at_start,
+                    workers
+                        .iter()
+                        .map(|w| format!(
+                            "{}/{}/{:.1}s",
+                            w.depth(),
+                            w.dropped(),
+                            w.stalled().as_secs_f64()
+                        ))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
             ),
         );
+
+        // End a bounded capture once any of its limits is reached. Task
+        // entries are counted by the analysis, which may lag behind.
+        if let Some(limits) = limits {
+            let task_entries = analyzer.counters().task_entries.load(Ordering::SeqCst);
+            stop_reason = match limits {
+                CaptureLimits {
+                    duration: Some(limit),
+                    ..
+                } if duration >= *limit => Some(format!("after {:.1}s", limit.as_secs_f64())),
+                CaptureLimits {
+                    max_packets: Some(max),
+                    ..
+                } if packets_received >= *max => {
+                    Some(format!("after {} packets", packets_received))
+                }
+                CaptureLimits {
+                    stop_on_task: Some(task),
+                    stop_on_task_count: count,
+                    ..
+                } if task_entries >= count.unwrap_or(1) => Some(format!(
+                    "after task {} was entered {} time(s)",
+                    task, task_entries
+                )),
+                _ => None,
+            };
+            if stop_reason.is_some() {
+                break;
+            }
+        }
     }
 
-    // close frontend sockets
-    drop(sinks);
+    eprintln!(); // don't overwrite the status line
+    if let Some(reason) = stop_reason {
+        log::status("Stopped", reason);
+    }
 
-    retstatus.map_err(|e| e.into())
+    // Stop reading from the source. The reader thread is not joined: it
+    // may be blocked on a source that yields no more data.
+    drop(source);
+
+    // Wait for the sinks to drain their queues and finalize.
+    for worker in workers.drain(..) {
+        if worker.dropped() > 0 {
+            log::warn(format!(
+                "{} could not keep up: {} packet groups were dropped",
+                worker.describe(),
+                worker.dropped()
+            ));
+        }
+        if worker.stalled() > std::time::Duration::ZERO {
+            log::warn(format!(
+                "{} could not keep up: trace data was not forwarded for {:.1}s while waiting on it",
+                worker.describe(),
+                worker.stalled().as_secs_f64()
+            ));
+        }
+        worker.finish();
+    }
+    if analyzer.stalled() > std::time::Duration::ZERO {
+        log::warn(format!(
+            "session analysis could not keep up: trace data was not forwarded for {:.1}s while waiting on it",
+            analyzer.stalled().as_secs_f64()
+        ));
+    }
+
+    // Report per-task timing statistics of the session
+    let (report, violations) = analyzer.finish();
+    if let Some(statistics) = statistics {
+        match statistics.format {
+            OutputFormat::Text => println!("{}", report),
+            OutputFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(&report).context("Failed to serialize statistics")?
+            ),
+        }
+    }
+
+    retstatus?;
+    match violations {
+        0 => Ok(()),
+        n => Err(RTICScopeError::TimingViolations(n)),
+    }
 }
 
 type TraceTuple = (
@@ -530,6 +1037,185 @@ type TraceTuple = (
     recovery::Metadata,
 );
 
+/// Builds the target application, and creates a wrapper around cargo
+/// that reuses the target directory of the application.
+fn build(cargo_options: &CargoOptions) -> Result<(CargoWrapper, Artifact), RTICScopeError> {
+    log::status("Building", "RTIC target application...".to_string());
+    Ok(CargoWrapper::new(
+        &env::current_dir()?,
+        cargo_options.to_cargo_options(),
+    )?)
+}
+
+/// Recovers the metadata of the built application and prepares the
+/// source and sinks via `prepare_sources`, which also prepares the
+/// target if `target` describes how. Returns the name of the built
+/// binary alongside.
+fn prepare(
+    artifact: &Artifact,
+    target: Option<&str>,
+    prepare_sources: impl FnOnce() -> Result<Option<TraceTuple>, RTICScopeError>,
+) -> Result<Option<(TraceTuple, String)>, RTICScopeError> {
+    let prog = format!("{} ({})", artifact.target.name, artifact.target.src_path,);
+    log::status(
+        "Recovering",
+        format!(
+            "metadata for {}{}",
+            prog,
+            if target.is_some() {
+                " and preparing target..."
+            } else {
+                "..."
+            }
+        ),
+    );
+
+    let (source, sinks, metadata) = match prepare_sources()? {
+        Some(tup) => tup,
+        None => return Ok(None),
+    };
+
+    log::status(
+        "Recovered",
+        format!(
+            "{ntotal} task(s) from {prog}: {nhard} hard, {nsoft} soft.{prepared}",
+            ntotal = metadata.hardware_tasks() + metadata.software_tasks(),
+            prog = artifact.target.name,
+            nhard = metadata.hardware_tasks(),
+            nsoft = metadata.software_tasks(),
+            prepared = target.map_or(String::new(), |target| format!(" {}.", target)),
+        ),
+    );
+
+    Ok(Some((
+        (source, sinks, metadata),
+        artifact.target.name.clone(),
+    )))
+}
+
+/// A trace as printed by `replay --list`.
+#[derive(Serialize)]
+struct TraceListing {
+    index: usize,
+    path: PathBuf,
+    program: Option<String>,
+    git_desc: Option<String>,
+    reset_timestamp: chrono::DateTime<chrono::Local>,
+    /// Whether the target was reset at `reset_timestamp`, rather than
+    /// attached to while running.
+    reset_known: bool,
+    hardware_tasks: usize,
+    software_tasks: usize,
+    /// Summary of the trace content, if the trace could be read in
+    /// full.
+    #[serde(flatten)]
+    summary: Option<sources::TraceSummary>,
+    comment: String,
+}
+
+/// Samples the trace stream on `device` at the given candidate rates,
+/// or common SWO rates and `configured` if none, and returns the most
+/// plausible rate.
+fn detect_baud(device: &str, candidates: &[u32], configured: u32) -> Result<u32, RTICScopeError> {
+    let candidates = if candidates.is_empty() {
+        let mut candidates = sources::baud::DEFAULT_CANDIDATES.to_vec();
+        if !candidates.contains(&configured) {
+            candidates.push(configured);
+        }
+        candidates
+    } else {
+        candidates.to_vec()
+    };
+
+    log::status(
+        "Detecting",
+        format!(
+            "SWO baud rate on {} ({} candidates)",
+            device,
+            candidates.len()
+        ),
+    );
+    let assessed = sources::baud::detect(device, &candidates)?;
+    if !assessed.is_empty() {
+        log::status(
+            "Sampled",
+            assessed
+                .iter()
+                .map(|candidate| candidate.to_string())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        );
+    }
+
+    match assessed.first() {
+        Some(best) if best.score() > 0.0 => {
+            if best.baud != configured {
+                log::warn(format!(
+                    "detected SWO baud rate {} differs from the configured rate {}",
+                    best.baud, configured
+                ));
+            }
+            log::status("Detected", format!("SWO baud rate {}", best.baud));
+            Ok(best.baud)
+        }
+        _ => Err(sources::SourceError::NoBaudRate(device.to_string()).into()),
+    }
+}
+
+/// Finds and opens all traces in the given directory, having read
+/// their metadata. Traces are sorted by their reset timestamp, oldest
+/// first, so that indices are stable as long as no traces are added or
+/// removed. Unreadable traces are skipped.
+fn indexed_trace_files(
+    dir: PathBuf,
+) -> Result<Vec<(PathBuf, sources::FileSource)>, RTICScopeError> {
+    let mut traces: Vec<_> = sinks::file::find_trace_files(dir)?
+        .filter_map(|trace| match open_trace_file(&trace, false) {
+            Ok(src) => Some((trace, src)),
+            Err(e) => {
+                log::warn(format!("skipping {}: {}", trace.display(), e));
+                None
+            }
+        })
+        .collect();
+    traces.sort_by_cached_key(|(path, src)| (src.metadata().reset_timestamp(), path.clone()));
+
+    Ok(traces)
+}
+
+fn open_trace_file(path: &Path, redecode: bool) -> Result<sources::FileSource, RTICScopeError> {
+    let mut src = sources::FileSource::new(fs::OpenOptions::new().read(true).open(path)?)?;
+    if redecode {
+        src.redecode();
+    }
+
+    Ok(src)
+}
+
+fn export_sinks(
+    opts: &ExportOptions,
+    metadata: &recovery::Metadata,
+) -> Result<Vec<Box<dyn sinks::Sink>>, RTICScopeError> {
+    let create = |path: &PathBuf| {
+        fs::File::create(path).map_err(|e| {
+            sinks::SinkError::SetupIOError(
+                Some(format!("Failed to create export file {}", path.display())),
+                e,
+            )
+        })
+    };
+    let mut sinks: Vec<Box<dyn sinks::Sink>> = vec![];
+
+    if let Some(path) = &opts.chrome {
+        sinks.push(Box::new(sinks::ChromeSink::new(create(path)?, metadata)?));
+    }
+    if let Some(path) = &opts.vcd {
+        sinks.push(Box::new(sinks::VcdSink::new(create(path)?, metadata)?));
+    }
+
+    Ok(sinks)
+}
+
 fn resolve_maps(
     cargo: &CargoWrapper,
     pac: &ManifestOptions,
@@ -556,11 +1242,14 @@ fn trace(
         return Ok(None);
     }
 
-    let mut session = opts
-        .flash_options
-        .probe_options
-        .simple_attach()
-        .context("Failed to attach to target session")?;
+    // Read the RTIC Scope manifest metadata block
+    let mut manip = manifest::ManifestProperties::new(cargo, Some(&opts.pac))?;
+
+    // Refuse triggers that can never fire before touching the target.
+    let task_names = maps.task_names();
+    for trigger in &opts.trigger.triggers {
+        trigger.validate(&task_names, &manip.constraints)?;
+    }
 
     // TODO make this into Sink::generate().remove_old(), etc.?
     let mut trace_sink = sinks::FileSink::generate_trace_file(
@@ -569,46 +1258,126 @@ fn trace(
             .as_ref()
             .unwrap_or(&cargo.target_dir().join("rtic-traces")),
         opts.remove_prev_traces,
+        opts.compress,
     )
     .context("Failed to generate trace sink file")?;
 
-    // Flash binary to target
-    let elf = artifact.executable.as_ref().unwrap();
-    let flashloader = opts
-        .flash_options
-        .probe_options
-        .build_flashloader(&mut session, &elf.clone().into_std_path_buf())?;
-    flash::run_flash_download(
-        &mut session,
-        &elf.clone().into_std_path_buf(),
-        &opts.flash_options,
-        flashloader,
-    )?;
+    // If the trace stream is captured by an external tool, leave the
+    // target be.
+    let mut trace_source: Box<dyn sources::Source> = if let Some(input) = &opts.raw_input {
+        Box::new(
+            sources::PipeSource::open(input)
+                .with_context(|| format!("Failed to open {}", input.display()))?,
+        )
+    } else if let Some(addr) = &opts.tcp {
+        Box::new(
+            sources::TcpSource::connect(addr)
+                .with_context(|| format!("Failed to connect to {}", addr))?,
+        )
+    } else {
+        let mut session = opts
+            .flash_options
+            .probe_options
+            .simple_attach()
+            .context("Failed to attach to target session")?;
+
+        let elf = artifact.executable.as_ref().unwrap();
+        if opts.attach {
+            attach::verify_firmware(&mut session, elf.as_std_path())?;
+            log::status(
+                "Verified",
+                format!("that the running target executes {}", elf),
+            );
+        } else {
+            // Flash binary to target
+            let flashloader = opts
+                .flash_options
+                .probe_options
+                .build_flashloader(&mut session, &elf.clone().into_std_path_buf())?;
+            flash::run_flash_download(
+                &mut session,
+                &elf.clone().into_std_path_buf(),
+                &opts.flash_options,
+                flashloader,
+            )?;
+        }
+
+        if opts.host_setup {
+            setup::configure(
+                &mut session,
+                elf.as_std_path(),
+                &manip,
+                opts.tpiu.source_id.is_some(),
+            )?;
+            log::status(
+                "Configured",
+                "tracing peripherals of the target".to_string(),
+            );
+        }
 
-    // Read the RTIC Scope manifest metadata block
-    let manip = manifest::ManifestProperties::new(cargo, Some(&opts.pac))?;
+        if let Some(dev) = &opts.serial {
+            if opts.detect_baud {
+                // The target must run to emit trace data.
+                session
+                    .core(0)
+                    .and_then(|mut core| core.reset())
+                    .map_err(sources::SourceError::ResetError)?;
+                manip.tpiu_baud = detect_baud(dev, &opts.baud_candidates, manip.tpiu_baud)?;
+            }
 
-    let mut trace_source: Box<dyn sources::Source> = if let Some(dev) = &opts.serial {
-        Box::new(sources::TTYSource::new(
-            sources::tty::configure(dev).with_context(|| format!("Failed to configure {}", dev))?,
-            session,
-        ))
-    } else {
-        Box::new(sources::ProbeSource::new(session, &manip)?)
+            Box::new(sources::TTYSource::new(
+                // Not wrapped in context, so that hints on rejected baud
+                // rates are rendered.
+                sources::tty::configure(dev, manip.tpiu_baud)?,
+                session,
+            ))
+        } else {
+            Box::new(sources::ProbeSource::new(session, &manip)?)
+        }
     };
+    if let Some(demux) = opts.tpiu.demux() {
+        trace_source.deframe(demux)?;
+    }
+    if let Some(timeout) = opts.stall_timeout {
+        trace_source.stall_after(timeout)?;
+    }
+    if opts.record_raw {
+        trace_source.record_raw();
+    }
 
     // Sample the timestamp of target reset, wait for trace clock
     // frequency payload, flush metadata to file.
     let metadata = trace_sink
-        .init(maps, manip.clone(), opts.comment.clone(), || {
-            // Reset the target to execute flashed binary
-            trace_source.reset_target(opts.flash_options.reset_halt)?;
+        .init(
+            maps,
+            manip.clone(),
+            opts.comment.clone(),
+            opts.attach,
+            || {
+                // Reset the target to execute flashed binary, if any
+                if !opts.attach {
+                    trace_source.reset_target(opts.flash_options.reset_halt)?;
+                }
 
-            Ok(manip.tpiu_freq)
-        })
+                Ok(manip.tpiu_freq)
+            },
+        )
         .context("Failed to initialize metadata")?;
 
-    Ok(Some((trace_source, vec![Box::new(trace_sink)], metadata)))
+    // Only record around triggers, if any.
+    let trace_sink: Box<dyn sinks::Sink> = if opts.trigger.triggers.is_empty() {
+        Box::new(trace_sink)
+    } else {
+        Box::new(sinks::TriggeredSink::new(
+            Box::new(trace_sink),
+            opts.trigger.triggers.clone(),
+            opts.trigger.pre,
+            opts.trigger.post,
+            &metadata,
+        ))
+    };
+
+    Ok(Some((trace_source, vec![trace_sink], metadata)))
 }
 
 fn replay(
@@ -620,60 +1389,165 @@ fn replay(
         ReplayOptions {
             raw_options:
                 RawFileOptions {
-                    file: Some(file),
+                    file,
+                    input,
                     comment,
                     pac,
+                    tpiu,
                 },
             ..
-        } => {
-            let src = sources::RawFileSource::new(fs::OpenOptions::new().read(true).open(file)?);
+        } if file.is_some() || input.is_some() => {
+            let mut src: Box<dyn sources::Source> = match (file, input) {
+                (Some(file), _) => Box::new(sources::RawFileSource::new(
+                    fs::OpenOptions::new().read(true).open(file)?,
+                )),
+                (None, Some(input)) => Box::new(sources::PipeSource::open(input)?),
+                _ => unreachable!(),
+            };
+            if let Some(demux) = tpiu.demux() {
+                src.deframe(demux)?;
+            }
             let maps = resolve_maps(cargo, pac, artifact)?;
-            let manip = manifest::ManifestProperties::new(cargo, None)?;
-            let metadata = recovery::Metadata::new(
-                maps,
-                manip,
-                chrono::Local::now(),
-                pac.tpiu_freq.unwrap(),
-                comment.clone(),
-            );
-
-            Ok(Some((Box::new(src), vec![], metadata)))
+            // Fall back to the manifest for what the options leave
+            // unset, e.g. the TPIU frequency, as when tracing.
+            let manip = manifest::ManifestProperties::new(cargo, Some(pac))?;
+            let freq = manip.tpiu_freq;
+            let metadata =
+                recovery::Metadata::new(maps, manip, chrono::Local::now(), freq, comment.clone());
+
+            Ok(Some((src, vec![], metadata)))
         }
         ReplayOptions {
             list: true,
             trace_dir,
+            format,
             ..
         } => {
-            let traces = sinks::file::find_trace_files(
+            let traces = indexed_trace_files(
                 trace_dir
                     .clone()
                     .unwrap_or(cargo.target_dir().join("rtic-traces")),
             )?;
-            for (i, trace) in traces.enumerate() {
-                let metadata =
-                    sources::FileSource::new(fs::OpenOptions::new().read(true).open(&trace)?)?
-                        .metadata();
-                println!("{}\t{}\t{}", i, trace.display(), metadata.comment());
+            let mut listings = vec![];
+            for (index, (path, src)) in traces.into_iter().enumerate() {
+                // A damaged trace is still listed, as its metadata is
+                // intact.
+                let metadata = src.metadata();
+                let summary = match src.summarize() {
+                    Ok(summary) => Some(summary),
+                    Err(e) => {
+                        log::warn(format!("cannot summarize {}: {}", path.display(), e));
+                        None
+                    }
+                };
+                listings.push(TraceListing {
+                    index,
+                    program: metadata.program().map(str::to_string),
+                    git_desc: metadata.git_desc().map(str::to_string),
+                    reset_timestamp: metadata.reset_timestamp(),
+                    reset_known: metadata.reset_known(),
+                    hardware_tasks: metadata.hardware_tasks(),
+                    software_tasks: metadata.software_tasks(),
+                    summary,
+                    comment: metadata.comment(),
+                    path,
+                });
+            }
+
+            match format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&listings)
+                        .context("Failed to serialize listing")?
+                ),
+                OutputFormat::Text => {
+                    println!("index\tpath\tprogram\treset timestamp\tduration\tpackets\tmalformed\toverflows\ttasks (hard/soft)\tcomment");
+                    for l in listings.iter() {
+                        let summarized = |count: fn(&sources::TraceSummary) -> usize| {
+                            l.summary
+                                .as_ref()
+                                .map_or("-".to_string(), |s| count(s).to_string())
+                        };
+                        println!(
+                            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}/{}\t{}",
+                            l.index,
+                            l.path.display(),
+                            match (&l.program, &l.git_desc) {
+                                (Some(prog), Some(desc)) => format!("{} (g{})", prog, desc),
+                                (Some(prog), None) => prog.to_string(),
+                                _ => "-".to_string(),
+                            },
+                            if l.reset_known {
+                                l.reset_timestamp.to_string()
+                            } else {
+                                format!("{} (attached)", l.reset_timestamp)
+                            },
+                            l.summary
+                                .as_ref()
+                                .map_or("-".to_string(), |s| format!("{:.3}s", s.duration)),
+                            summarized(|s| s.packets),
+                            summarized(|s| s.malformed),
+                            summarized(|s| s.overflows),
+                            l.hardware_tasks,
+                            l.software_tasks,
+                            l.comment,
+                        );
+                    }
+                }
             }
 
             Ok(None)
         }
         ReplayOptions {
-            index: Some(idx),
+            trace: selector,
+            latest,
             trace_dir,
+            redecode,
             ..
         } => {
-            let mut traces = sinks::file::find_trace_files(
+            let mut traces = indexed_trace_files(
                 trace_dir
                     .clone()
                     .unwrap_or(cargo.target_dir().join("rtic-traces")),
             )?;
-            let trace = traces
-                .nth(*idx)
-                .with_context(|| format!("No trace with index {}", *idx))?;
+            let mut src = match (selector, latest) {
+                (Some(TraceSelector::Index(_)), true) => {
+                    return Err(anyhow!("--latest cannot be combined with a trace index").into())
+                }
+                (Some(TraceSelector::Index(idx)), false) => traces
+                    .drain(..)
+                    .nth(*idx)
+                    .with_context(|| format!("No trace with index {}", *idx))?,
+                (Some(TraceSelector::Glob(pattern)), latest) => {
+                    traces.retain(|(trace, _)| {
+                        trace
+                            .file_name()
+                            .and_then(|name| name.to_str())
+                            .map_or(false, |name| pattern.matches(name))
+                    });
+                    match traces.len() {
+                        0 => return Err(anyhow!("No trace matches {}", pattern).into()),
+                        1 => traces.pop().unwrap(),
+                        _ if *latest => traces.pop().unwrap(),
+                        n => {
+                            return Err(anyhow!(
+                                "{} traces match {}; narrow the pattern or pass --latest",
+                                n,
+                                pattern
+                            )
+                            .into())
+                        }
+                    }
+                }
+                (None, true) => traces.pop().context("No traces found")?,
+                _ => unreachable!(),
+            }
+            .1;
 
-            // open trace file and print packets
-            let src = sources::FileSource::new(fs::OpenOptions::new().read(true).open(&trace)?)?;
+            // print packets of the trace file
+            if *redecode {
+                src.redecode();
+            }
             let metadata = src.metadata();
 
             Ok(Some((Box::new(src), vec![], metadata)))
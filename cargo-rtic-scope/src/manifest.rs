@@ -2,6 +2,7 @@ use crate::build::CargoWrapper;
 use crate::diag;
 use crate::ManifestOptions;
 
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 
 use serde::{Deserialize, Serialize};
@@ -18,6 +19,7 @@ struct ManifestPropertiesIntermediate {
     pub tpiu_baud: Option<u32>,
     pub dwt_enter_id: Option<usize>,
     pub dwt_exit_id: Option<usize>,
+    pub constraints: Option<BTreeMap<String, TaskConstraints>>,
 }
 
 impl Default for ManifestPropertiesIntermediate {
@@ -31,6 +33,7 @@ impl Default for ManifestPropertiesIntermediate {
             tpiu_baud: None,
             dwt_enter_id: None,
             dwt_exit_id: None,
+            constraints: None,
         }
     }
 }
@@ -61,6 +64,9 @@ impl ManifestPropertiesIntermediate {
         if self.dwt_exit_id.is_none() {
             self.dwt_exit_id = other.dwt_exit_id;
         }
+        if self.constraints.is_none() {
+            self.constraints = other.constraints;
+        }
     }
 }
 
@@ -74,6 +80,35 @@ pub struct ManifestProperties {
     pub tpiu_baud: u32,
     pub dwt_enter_id: usize,
     pub dwt_exit_id: usize,
+    /// Timing budgets of tasks, keyed by task name. Absent in traces
+    /// recorded by older versions.
+    #[serde(default)]
+    pub constraints: BTreeMap<String, TaskConstraints>,
+}
+
+/// Timing budget of a task, declared as a table under
+/// `[package.metadata.rtic-scope.constraints]`. For example:
+///
+/// ```toml
+/// [package.metadata.rtic-scope.constraints.blink]
+/// max_exec_us = 50
+/// max_elapsed_us = 200
+/// min_period_us = 1000
+/// ```
+///
+/// All budgets are optional and given in microseconds.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TaskConstraints {
+    /// Maximum time the task may execute, excluding preemption.
+    pub max_exec_us: Option<u64>,
+    /// Maximum time from task entry to exit, including preemption.
+    /// This is not the response time of the task: the moment a task is
+    /// released (pended or spawned) is not traced, so the time it waits
+    /// to be entered is not included.
+    pub max_elapsed_us: Option<u64>,
+    /// Minimum time between two consecutive entries of the task.
+    pub min_period_us: Option<u64>,
 }
 
 #[derive(Error, Debug)]
@@ -123,6 +158,7 @@ impl TryInto<ManifestProperties> for ManifestPropertiesIntermediate {
             tpiu_baud: self.tpiu_baud.ok_or(Self::Error::MissingBaud)?,
             dwt_enter_id: self.dwt_enter_id.ok_or(Self::Error::MissingDWTUnit)?,
             dwt_exit_id: self.dwt_exit_id.ok_or(Self::Error::MissingDWTUnit)?,
+            constraints: self.constraints.unwrap_or_default(),
         })
     }
 }
@@ -1,6 +1,7 @@
 use crate::build::{self, CargoWrapper};
 use crate::diag;
-use crate::manifest::ManifestProperties;
+use crate::manifest::{ManifestProperties, TaskConstraints};
+use crate::sources::DecodeOptions;
 
 use std::collections::BTreeMap;
 use std::fmt;
@@ -36,6 +37,8 @@ pub enum RecoveryError {
     MissingHWExceptionMap(HwExceptionNumber),
     #[error("The DataTraceValue ({0:?}) -> RTIC task mapping does not exist")]
     MissingSWMap(Vec<u8>),
+    #[error("Trace packets are not timestamped by a local timestamp")]
+    MissingTimestamp,
     #[error("Failed to read artifact source file: {0}")]
     SourceRead(#[source] std::io::Error),
     #[error("Failed to tokenize artifact source file: {0}")]
@@ -73,6 +76,21 @@ pub struct Metadata {
     timestamp: chrono::DateTime<Local>,
     freq: u32,
     comment: Option<String>,
+    /// Name of the traced binary. Unknown for traces recorded by older
+    /// versions.
+    #[serde(default)]
+    program: Option<String>,
+    /// `git describe` of the traced binary's repository.
+    #[serde(default)]
+    git_desc: Option<String>,
+    /// Whether the target was traced while running instead of being
+    /// reset. `timestamp` is then the moment tracing started, and the
+    /// moment of the last reset is unknown.
+    #[serde(default)]
+    reset_unknown: bool,
+    /// Options the trace stream was decoded with when captured.
+    #[serde(default)]
+    decode_options: DecodeOptions,
 }
 
 impl Metadata {
@@ -89,9 +107,35 @@ impl Metadata {
             timestamp,
             freq,
             comment,
+            program: None,
+            git_desc: None,
+            reset_unknown: false,
+            decode_options: DecodeOptions::CAPTURE,
         }
     }
 
+    /// Marks the moment of the last target reset as unknown.
+    pub fn with_unknown_reset(mut self) -> Self {
+        self.reset_unknown = true;
+        self
+    }
+
+    /// Records the name of the traced binary and the `git describe` of
+    /// its repository.
+    pub fn with_program(mut self, program: String, git_desc: Option<String>) -> Self {
+        self.program = Some(program);
+        self.git_desc = git_desc;
+        self
+    }
+
+    pub fn program(&self) -> Option<&str> {
+        self.program.as_deref()
+    }
+
+    pub fn git_desc(&self) -> Option<&str> {
+        self.git_desc.as_deref()
+    }
+
     pub fn hardware_tasks(&self) -> usize {
         self.maps.exceptions.len() + self.maps.interrupts.len()
     }
@@ -104,17 +148,60 @@ impl Metadata {
         self.comment.clone().unwrap_or("".to_string())
     }
 
-    pub fn build_event_chunk(&self, packets: TimestampedTracePackets) -> EventChunk {
+    /// The moment the target was reset, or the moment tracing started
+    /// if the reset is unknown.
+    pub fn reset_timestamp(&self) -> chrono::DateTime<Local> {
+        self.timestamp
+    }
+
+    pub fn reset_known(&self) -> bool {
+        !self.reset_unknown
+    }
+
+    pub fn freq(&self) -> u32 {
+        self.freq
+    }
+
+    pub fn decode_options(&self) -> DecodeOptions {
+        self.decode_options
+    }
+
+    /// Timing budgets of the traced tasks, as declared in the manifest
+    /// when the trace was recorded.
+    pub fn constraints(&self) -> &BTreeMap<String, TaskConstraints> {
+        &self.manip.constraints
+    }
+
+    /// Names of all tasks known by the resolve maps, as they appear in
+    /// [EventType::Task] events.
+    pub fn task_names(&self) -> Vec<String> {
+        self.maps.task_names()
+    }
+
+    /// Maps the packets to RTIC events. Panics if the packets are not
+    /// timestamped by a local timestamp, which those decoded with
+    /// [DecodeOptions::CAPTURE] always are. See
+    /// [Metadata::try_build_event_chunk].
+    pub fn build_event_chunk(&self, packets: &TimestampedTracePackets) -> EventChunk {
+        self.try_build_event_chunk(packets)
+            .expect("packets lack a local timestamp")
+    }
+
+    /// Maps the packets to RTIC events, unless they are not timestamped
+    /// by a local timestamp.
+    pub fn try_build_event_chunk(
+        &self,
+        packets: &TimestampedTracePackets,
+    ) -> Result<EventChunk, RecoveryError> {
         let timestamp = {
             let itm_decode::Timestamp {
                 base,
                 delta,
                 data_relation,
                 diverged,
-            } = packets.timestamp;
-            let seconds_since = (base.unwrap_or(0) + delta.expect("timestamp delta is None"))
-                as f64
-                / self.freq as f64;
+            } = packets.timestamp.clone();
+            let delta = delta.ok_or(RecoveryError::MissingTimestamp)?;
+            let seconds_since = (base.unwrap_or(0) + delta) as f64 / self.freq as f64;
             let since = chrono::Duration::nanoseconds((seconds_since * 1e9).round() as i64);
 
             api::Timestamp {
@@ -213,7 +300,7 @@ impl Metadata {
                 .collect(),
         );
 
-        EventChunk { timestamp, events }
+        Ok(EventChunk { timestamp, events })
     }
 }
 
@@ -234,6 +321,23 @@ pub struct TaskResolveMaps {
     pub sw_assocs: SwAssocs,
 }
 
+impl TaskResolveMaps {
+    /// Names of all resolved tasks, as they appear in [EventType::Task]
+    /// events.
+    pub fn task_names(&self) -> Vec<String> {
+        self.exceptions
+            .values()
+            .map(|task| task.join("::"))
+            .chain(
+                self.interrupts
+                    .values()
+                    .map(|(task, _bind)| task.join("::")),
+            )
+            .chain(self.sw_assocs.values().map(|task| task.join("::")))
+            .collect()
+    }
+}
+
 impl fmt::Display for TaskResolveMaps {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // Here C++ reigns superior with its generic lambdas.
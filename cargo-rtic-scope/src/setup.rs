@@ -0,0 +1,261 @@
+//! Host-side configuration of the tracing peripherals of the target
+//! through the probe. Mirrors `cortex_m_rtic_trace::setup`, so that
+//! applications that do not call it can be traced.
+use crate::diag;
+use crate::manifest::ManifestProperties;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use object::{Object, ObjectSymbol};
+use probe_rs::{Core, MemoryInterface, Session};
+use thiserror::Error;
+
+/// Addresses and fields of the ARMv7-M debug registers involved in
+/// tracing.
+pub mod reg {
+    /// Debug Exception and Monitor Control Register.
+    pub const DEMCR: u32 = 0xE000_EDFC;
+    pub const DEMCR_TRCENA: u32 = 1 << 24;
+
+    /// ITM Lock Access Register, and the key that unlocks the ITM.
+    pub const ITM_LAR: u32 = 0xE000_0FB0;
+    pub const ITM_LAR_KEY: u32 = 0xC5AC_CE55;
+    /// ITM Trace Control Register.
+    pub const ITM_TCR: u32 = 0xE000_0E80;
+    pub const ITM_TCR_ITMENA: u32 = 1 << 0;
+    pub const ITM_TCR_TSENA: u32 = 1 << 1;
+    pub const ITM_TCR_TXENA: u32 = 1 << 3;
+    pub const ITM_TCR_TRACEBUSID_SHIFT: u32 = 16;
+
+    /// TPIU Asynchronous Clock Prescaler Register.
+    pub const TPIU_ACPR: u32 = 0xE004_0010;
+    /// TPIU Selected Pin Protocol Register.
+    pub const TPIU_SPPR: u32 = 0xE004_00F0;
+    pub const TPIU_SPPR_PARALLEL: u32 = 0b00;
+    pub const TPIU_SPPR_MANCHESTER: u32 = 0b01;
+    pub const TPIU_SPPR_NRZ: u32 = 0b10;
+    /// TPIU Formatter and Flush Control Register.
+    pub const TPIU_FFCR: u32 = 0xE004_0304;
+    pub const TPIU_FFCR_ENFCONT: u32 = 1 << 1;
+    pub const TPIU_FFCR_TRIGIN: u32 = 1 << 8;
+
+    /// DWT Control Register.
+    pub const DWT_CTRL: u32 = 0xE000_1000;
+    pub const DWT_CTRL_EXCTRCENA: u32 = 1 << 16;
+    pub const DWT_CTRL_PCSAMPLENA: u32 = 1 << 12;
+    pub const DWT_CTRL_NUMCOMP_SHIFT: u32 = 28;
+
+    /// DWT Comparator, Mask, and Function Registers of comparator `n`.
+    pub const fn dwt_comp(n: usize) -> u32 {
+        0xE000_1020 + 16 * n as u32
+    }
+    pub const fn dwt_mask(n: usize) -> u32 {
+        0xE000_1024 + 16 * n as u32
+    }
+    pub const fn dwt_function(n: usize) -> u32 {
+        0xE000_1028 + 16 * n as u32
+    }
+    /// Emit a data trace packet with the written value on a write to
+    /// the comparator address.
+    pub const DWT_FUNCTION_DATA_WRITE: u32 = 0b1101;
+
+    /// STM32 Debug MCU Configuration Register.
+    pub const STM32_DBGMCU_CR: u32 = 0xE004_2004;
+    /// Enables the trace pins. Leaving TRACE_MODE cleared selects
+    /// asynchronous (SWO) output.
+    pub const STM32_DBGMCU_CR_TRACE_IOEN: u32 = 1 << 5;
+    pub const STM32_DBGMCU_CR_TRACE_MODE: u32 = 0b11 << 6;
+}
+
+/// ATB ID of the ITM, as set by `cortex_m_rtic_trace::setup`.
+pub const ITM_BUS_ID: u32 = 1;
+
+/// Name of the static the `#[trace]` macro writes task IDs to, which
+/// the DWT comparators watch.
+const WATCH_VARIABLES: &str = "WATCH_VARIABLES";
+
+#[derive(Debug, Error)]
+pub enum SetupError {
+    #[error("Failed to read {}: {1}", .0.display())]
+    ReadElf(PathBuf, #[source] std::io::Error),
+    #[error("Failed to parse {}: {1}", .0.display())]
+    ParseElf(PathBuf, #[source] object::read::Error),
+    #[error("{} does not contain the software task watch variables of cortex-m-rtic-trace", .0.display())]
+    NoWatchVariables(PathBuf),
+    #[error("DWT comparator {0} is assigned to software task tracing, but the target only has {1} comparators")]
+    NotEnoughComparators(usize, usize),
+    #[error("A baud rate of {baud} cannot be derived from a trace clock of {freq} Hz")]
+    InvalidBaud { freq: u32, baud: u32 },
+    #[error("Failed to access the debug registers of the target: {0}")]
+    ProbeError(#[source] probe_rs::Error),
+}
+
+impl diag::DiagnosableError for SetupError {
+    fn diagnose(&self) -> Vec<String> {
+        match self {
+            Self::NoWatchVariables(_) => vec![
+                "Software tasks are only traced if the application depends on cortex-m-rtic-trace and marks them with #[trace].".to_string(),
+            ],
+            Self::NotEnoughComparators(_, n) => vec![
+                format!("Set dwt_enter_id and dwt_exit_id in [package.metadata.rtic-scope] to distinct comparators below {}.", n),
+            ],
+            Self::InvalidBaud { .. } => vec![
+                "tpiu_baud in [package.metadata.rtic-scope] must be a non-zero rate no higher than tpiu_freq, the frequency of the trace clock.".to_string(),
+            ],
+            _ => vec![],
+        }
+    }
+}
+
+/// Returns the number of DWT comparators implemented by the target.
+pub fn dwt_comparators(core: &mut Core) -> Result<usize, SetupError> {
+    let ctrl = core
+        .read_word_32(reg::DWT_CTRL)
+        .map_err(SetupError::ProbeError)?;
+    Ok((ctrl >> reg::DWT_CTRL_NUMCOMP_SHIFT) as usize)
+}
+
+/// STM32 families whose debug MCU enables the trace pins via
+/// TRACE_IOEN and TRACE_MODE in a DBGMCU_CR at 0xE004_2004.
+const STM32_DBGMCU_FAMILIES: [&str; 5] = ["stm32f1", "stm32f2", "stm32f3", "stm32f4", "stm32l1"];
+
+/// Whether the target is an STM32 device whose trace pins are enabled
+/// via the debug MCU at [reg::STM32_DBGMCU_CR]. The trace pins of other
+/// devices are left as configured by the target.
+pub fn has_stm32_dbgmcu(manip: &ManifestProperties) -> bool {
+    STM32_DBGMCU_FAMILIES
+        .iter()
+        .any(|family| manip.pac_name.starts_with(family))
+}
+
+/// Returns the value of the TPIU prescaler, TPIU_ACPR, that derives the
+/// given baud rate from the trace clock frequency.
+pub fn prescaler(freq: u32, baud: u32) -> Result<u32, SetupError> {
+    if baud == 0 || baud > freq {
+        return Err(SetupError::InvalidBaud { freq, baud });
+    }
+    Ok(freq / baud - 1)
+}
+
+/// Finds the address of the software task watch variables in the given
+/// ELF. The entered task ID is written to the returned address, and the
+/// exited task ID to the word following it.
+pub fn watch_address(elf: &Path) -> Result<u32, SetupError> {
+    let bytes = fs::read(elf).map_err(|e| SetupError::ReadElf(elf.to_path_buf(), e))?;
+    let file =
+        object::File::parse(&*bytes).map_err(|e| SetupError::ParseElf(elf.to_path_buf(), e))?;
+
+    // The symbol is mangled, so match on its name only.
+    file.symbols()
+        .find(|sym| {
+            sym.name()
+                .map(|name| name.contains(WATCH_VARIABLES))
+                .unwrap_or(false)
+        })
+        .map(|sym| sym.address() as u32)
+        .ok_or_else(|| SetupError::NoWatchVariables(elf.to_path_buf()))
+}
+
+/// Configures DCB, TPIU, DWT, and ITM of the target for RTIC task
+/// tracing, and assigns the DWT comparators given by `dwt_enter_id` and
+/// `dwt_exit_id` to the software task watch variables found in `elf`.
+/// Trace output is enabled on STM32 devices. The TPIU formatter is
+/// enabled if `formatting`. The configuration survives a reset of the
+/// target, but not a power cycle. Nothing is written if `tpiu_baud`
+/// cannot be derived from `tpiu_freq`.
+pub fn configure(
+    session: &mut Session,
+    elf: &Path,
+    manip: &ManifestProperties,
+    formatting: bool,
+) -> Result<(), SetupError> {
+    let acpr = prescaler(manip.tpiu_freq, manip.tpiu_baud)?;
+    let watch = watch_address(elf)?;
+    let mut core = session.core(0).map_err(SetupError::ProbeError)?;
+
+    let comparators = dwt_comparators(&mut core)?;
+    for &id in &[manip.dwt_enter_id, manip.dwt_exit_id] {
+        if id >= comparators {
+            return Err(SetupError::NotEnoughComparators(id, comparators));
+        }
+    }
+
+    write_registers(&mut core, manip, acpr, watch, formatting).map_err(SetupError::ProbeError)
+}
+
+fn modify(core: &mut Core, addr: u32, set: u32, clear: u32) -> Result<(), probe_rs::Error> {
+    let value = core.read_word_32(addr)?;
+    core.write_word_32(addr, (value & !clear) | set)
+}
+
+fn write_registers(
+    core: &mut Core,
+    manip: &ManifestProperties,
+    acpr: u32,
+    watch: u32,
+    formatting: bool,
+) -> Result<(), probe_rs::Error> {
+    // Enable tracing
+    modify(core, reg::DEMCR, reg::DEMCR_TRCENA, 0)?;
+
+    // Trace pins of the STM32 debug MCU
+    if has_stm32_dbgmcu(manip) {
+        modify(
+            core,
+            reg::STM32_DBGMCU_CR,
+            reg::STM32_DBGMCU_CR_TRACE_IOEN,
+            reg::STM32_DBGMCU_CR_TRACE_MODE,
+        )?;
+    }
+
+    core.write_word_32(reg::TPIU_ACPR, acpr)?;
+    core.write_word_32(reg::TPIU_SPPR, reg::TPIU_SPPR_NRZ)?;
+    core.write_word_32(
+        reg::TPIU_FFCR,
+        if formatting {
+            reg::TPIU_FFCR_TRIGIN | reg::TPIU_FFCR_ENFCONT
+        } else {
+            reg::TPIU_FFCR_TRIGIN
+        },
+    )?;
+
+    modify(
+        core,
+        reg::DWT_CTRL,
+        reg::DWT_CTRL_EXCTRCENA,
+        reg::DWT_CTRL_PCSAMPLENA,
+    )?;
+    for (id, addr) in [(manip.dwt_enter_id, watch), (manip.dwt_exit_id, watch + 4)] {
+        core.write_word_32(reg::dwt_comp(id), addr)?;
+        core.write_word_32(reg::dwt_mask(id), 0)?;
+        core.write_word_32(reg::dwt_function(id), reg::DWT_FUNCTION_DATA_WRITE)?;
+    }
+
+    core.write_word_32(reg::ITM_LAR, reg::ITM_LAR_KEY)?;
+    core.write_word_32(
+        reg::ITM_TCR,
+        reg::ITM_TCR_ITMENA
+            | reg::ITM_TCR_TSENA
+            | reg::ITM_TCR_TXENA
+            | (ITM_BUS_ID << reg::ITM_TCR_TRACEBUSID_SHIFT),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prescalers() {
+        assert_eq!(prescaler(16_000_000, 2_000_000).unwrap(), 7);
+        assert_eq!(prescaler(16_000_000, 16_000_000).unwrap(), 0);
+        assert_eq!(prescaler(16_000_000, 115_200).unwrap(), 137);
+        for &baud in &[0, 16_000_001] {
+            assert!(matches!(
+                prescaler(16_000_000, baud),
+                Err(SetupError::InvalidBaud { baud: b, .. }) if b == baud
+            ));
+        }
+    }
+}
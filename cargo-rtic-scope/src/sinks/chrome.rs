@@ -0,0 +1,225 @@
+use crate::recovery::Metadata;
+use crate::sinks::{Packets, Sink, SinkError};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+use chrono::Local;
+use rtic_scope_api::{EventType, TaskAction};
+use serde_json::json;
+
+/// Process ID under which all tasks are grouped in the exported trace.
+const PID: usize = 1;
+
+/// Track on which global events (overflows, malformed packets) are
+/// placed. Task tracks are numbered from 1 and upwards.
+const GLOBAL_TID: usize = 0;
+
+/// Name of the slices during which a preempted task executes again
+/// after being returned to.
+const RESUMED: &str = "resumed";
+
+/// Exports the event stream in the Trace Event Format, as understood
+/// by Perfetto and chrome://tracing. Each task is given its own track
+/// on which each entry/exit pair is rendered as a duration slice.
+/// Within it, each interval from the moment a preempted task is
+/// returned to until it is preempted again or exits is rendered as a
+/// nested "resumed" slice.
+pub struct ChromeSink {
+    writer: BufWriter<fs::File>,
+    reset_timestamp: chrono::DateTime<Local>,
+    /// Task name -> track ID.
+    tracks: BTreeMap<String, usize>,
+    /// Task name -> number of slices not yet closed.
+    open_slices: BTreeMap<String, usize>,
+    /// Track of the open "resumed" slice, if any. Only the executing
+    /// task can have one.
+    resumed: Option<usize>,
+    last_ts: f64,
+    first: bool,
+}
+
+impl ChromeSink {
+    pub fn new(file: fs::File, metadata: &Metadata) -> Result<Self, SinkError> {
+        let mut writer = BufWriter::new(file);
+        writer.write_all(b"[\n").map_err(|e| {
+            SinkError::SetupIOError(Some("Failed to write trace event header".to_string()), e)
+        })?;
+
+        Ok(Self {
+            writer,
+            reset_timestamp: metadata.reset_timestamp(),
+            tracks: BTreeMap::new(),
+            open_slices: BTreeMap::new(),
+            resumed: None,
+            last_ts: 0.0,
+            first: true,
+        })
+    }
+
+    fn write_event(&mut self, event: serde_json::Value) -> Result<(), SinkError> {
+        let json = serde_json::to_string(&event)?;
+        let sep = if self.first { "" } else { ",\n" };
+        self.first = false;
+        write!(self.writer, "{}{}", sep, json).map_err(SinkError::DrainIOError)
+    }
+
+    /// Returns the track of the given task, announcing it in the trace
+    /// if it has not been seen before.
+    fn track(&mut self, task: &str) -> Result<usize, SinkError> {
+        if let Some(tid) = self.tracks.get(task) {
+            return Ok(*tid);
+        }
+
+        let tid = self.tracks.len() + 1;
+        self.tracks.insert(task.to_string(), tid);
+        self.write_event(json!({
+            "name": "thread_name",
+            "ph": "M",
+            "pid": PID,
+            "tid": tid,
+            "args": { "name": task },
+        }))?;
+
+        Ok(tid)
+    }
+
+    /// Closes the open "resumed" slice, if any.
+    fn end_resumed(&mut self, ts: f64) -> Result<(), SinkError> {
+        match self.resumed.take() {
+            Some(tid) => self.write_event(json!({
+                "ph": "E",
+                "ts": ts,
+                "pid": PID,
+                "tid": tid,
+            })),
+            None => Ok(()),
+        }
+    }
+
+    /// Microseconds since target reset.
+    fn micros_since_reset(&self, ts: chrono::DateTime<Local>) -> f64 {
+        (ts - self.reset_timestamp).num_nanoseconds().unwrap_or(0) as f64 / 1e3
+    }
+}
+
+impl Sink for ChromeSink {
+    fn drain(&mut self, packets: Arc<Packets>) -> Result<(), SinkError> {
+        let chunk = packets.chunk();
+        let ts = self.micros_since_reset(chunk.timestamp.ts);
+        self.last_ts = ts;
+
+        for event in chunk.events.iter() {
+            match event {
+                EventType::Task { name, action } => {
+                    // Only tasks whose slice is open can be resumed
+                    // within it. Returns to thread mode, and to tasks
+                    // entered before the trace started, are dropped.
+                    if matches!(action, TaskAction::Returned)
+                        && !matches!(self.open_slices.get(name), Some(n) if *n > 0)
+                    {
+                        continue;
+                    }
+                    let tid = self.track(name)?;
+                    match action {
+                        TaskAction::Entered => {
+                            // The executing task, if resumed, is
+                            // preempted.
+                            self.end_resumed(ts)?;
+                            *self.open_slices.entry(name.clone()).or_insert(0) += 1;
+                            self.write_event(json!({
+                                "name": name,
+                                "ph": "B",
+                                "ts": ts,
+                                "pid": PID,
+                                "tid": tid,
+                            }))?;
+                        }
+                        TaskAction::Exited => {
+                            // A slice can only be closed if we saw it
+                            // open. Exits of tasks that were entered
+                            // before the trace started are dropped.
+                            match self.open_slices.get_mut(name) {
+                                Some(n) if *n > 0 => *n -= 1,
+                                _ => continue,
+                            }
+                            if self.resumed == Some(tid) {
+                                self.end_resumed(ts)?;
+                            }
+                            self.write_event(json!({
+                                "ph": "E",
+                                "ts": ts,
+                                "pid": PID,
+                                "tid": tid,
+                            }))?;
+                        }
+                        TaskAction::Returned => {
+                            self.end_resumed(ts)?;
+                            self.resumed = Some(tid);
+                            self.write_event(json!({
+                                "name": RESUMED,
+                                "ph": "B",
+                                "ts": ts,
+                                "pid": PID,
+                                "tid": tid,
+                            }))?;
+                        }
+                    }
+                }
+                EventType::Overflow => self.write_event(json!({
+                    "name": "overflow",
+                    "ph": "i",
+                    "s": "g",
+                    "ts": ts,
+                    "pid": PID,
+                    "tid": GLOBAL_TID,
+                }))?,
+                EventType::Invalid(malformed) => self.write_event(json!({
+                    "name": "malformed packet",
+                    "ph": "i",
+                    "s": "g",
+                    "ts": ts,
+                    "pid": PID,
+                    "tid": GLOBAL_TID,
+                    "args": { "reason": malformed.to_string() },
+                }))?,
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<(), SinkError> {
+        // Close any slices still open so that they are rendered up to
+        // the end of the trace, innermost first.
+        self.end_resumed(self.last_ts)?;
+        let open: Vec<(usize, usize)> = self
+            .open_slices
+            .iter()
+            .map(|(task, n)| (self.tracks[task], *n))
+            .collect();
+        for (tid, n) in open {
+            for _ in 0..n {
+                self.write_event(json!({
+                    "ph": "E",
+                    "ts": self.last_ts,
+                    "pid": PID,
+                    "tid": tid,
+                }))?;
+            }
+        }
+        self.open_slices.clear();
+
+        self.writer
+            .write_all(b"\n]\n")
+            .and_then(|_| self.writer.flush())
+            .map_err(SinkError::DrainIOError)
+    }
+
+    fn describe(&self) -> String {
+        format!("Trace Event Format sink: {:?}", self.writer.get_ref())
+    }
+}
@@ -1,22 +1,85 @@
+use crate::container::{self, RecordKind};
 use crate::manifest::ManifestProperties;
 use crate::recovery::{Metadata, TaskResolveMaps};
-use crate::sinks::{Sink, SinkError};
-use crate::TraceData;
+use crate::sinks::{Packets, Sink, SinkError};
 use std::fs;
 
-use std::io::Write;
+use std::io::{self, BufWriter, Write};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use cargo_metadata::Artifact;
 use chrono::prelude::*;
 use git2::{DescribeFormatOptions, DescribeOptions, Repository};
-use rtic_scope_api as api;
-use serde_json;
 
 const TRACE_FILE_EXT: &str = ".trace";
+const COMPRESSED_TRACE_FILE_EXT: &str = ".trace.zst";
+
+/// zstd compression level used for compressed traces.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// How often the compressor is flushed so that an interrupted
+/// recording loses at most this much trace data.
+const COMPRESSION_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+enum Output {
+    Plain(BufWriter<fs::File>),
+    Compressed {
+        encoder: zstd::Encoder<'static, BufWriter<fs::File>>,
+        last_flush: Instant,
+    },
+}
+
+impl Output {
+    fn file(&self) -> &fs::File {
+        match self {
+            Self::Plain(w) => w.get_ref(),
+            Self::Compressed { encoder, .. } => encoder.get_ref().get_ref(),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(w) => w.flush(),
+            Self::Compressed { encoder, .. } => {
+                encoder.do_finish()?;
+                encoder.get_mut().flush()
+            }
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(w) => w.write(buf),
+            Self::Compressed {
+                encoder,
+                last_flush,
+            } => {
+                let n = encoder.write(buf)?;
+                if last_flush.elapsed() >= COMPRESSION_FLUSH_INTERVAL {
+                    encoder.flush()?;
+                    *last_flush = Instant::now();
+                }
+                Ok(n)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(w) => w.flush(),
+            Self::Compressed { encoder, .. } => encoder.flush(),
+        }
+    }
+}
 
 pub struct FileSink {
-    file: fs::File,
+    file: Output,
+    program: String,
+    git_desc: String,
 }
 
 impl FileSink {
@@ -24,6 +87,7 @@ impl FileSink {
         artifact: &Artifact,
         trace_dir: &PathBuf,
         remove_prev_traces: bool,
+        compress: bool,
     ) -> Result<Self, SinkError> {
         if remove_prev_traces {
             if let Ok(traces) = find_trace_files(trace_dir.to_path_buf()) {
@@ -51,7 +115,14 @@ impl FileSink {
         let date = Local::now().format("%Y-%m-%dT%H:%M:%S").to_string();
         let file = trace_dir.join(format!(
             "{}-g{}-{}{}",
-            artifact.target.name, git_shortdesc, date, TRACE_FILE_EXT,
+            artifact.target.name,
+            git_shortdesc,
+            date,
+            if compress {
+                COMPRESSED_TRACE_FILE_EXT
+            } else {
+                TRACE_FILE_EXT
+            },
         ));
 
         fs::create_dir_all(trace_dir).map_err(|e| {
@@ -77,16 +148,38 @@ impl FileSink {
                 )
             })?;
 
-        Ok(Self { file })
+        let file = BufWriter::new(file);
+        let file = if compress {
+            Output::Compressed {
+                encoder: zstd::Encoder::new(file, COMPRESSION_LEVEL).map_err(|e| {
+                    SinkError::SetupIOError(
+                        Some("Failed to setup trace compression".to_string()),
+                        e,
+                    )
+                })?,
+                last_flush: Instant::now(),
+            }
+        } else {
+            Output::Plain(file)
+        };
+
+        Ok(Self {
+            file,
+            program: artifact.target.name.clone(),
+            git_desc: git_shortdesc,
+        })
     }
 
     /// Initializes the sink with metadata: task resolve maps and target
-    /// reset timestamp.
+    /// reset timestamp. If `reset_unknown`, the target is expected to
+    /// keep running and the timestamp marks the start of the trace
+    /// instead.
     pub fn init<F>(
         &mut self,
         maps: TaskResolveMaps,
         manip: ManifestProperties,
         comment: Option<String>,
+        reset_unknown: bool,
         reset_fun: F,
     ) -> Result<Metadata, SinkError>
     where
@@ -96,12 +189,17 @@ impl FileSink {
         let freq = reset_fun()?;
 
         // Create a trace file header with metadata (maps, reset
-        // timestamp, trace clock frequency). Any bytes after this
-        // sequence refers to trace packets.
-        let metadata = Metadata::new(maps, manip, ts, freq, comment);
+        // timestamp, trace clock frequency). Any records after this
+        // one refers to trace packets.
+        let mut metadata = Metadata::new(maps, manip, ts, freq, comment)
+            .with_program(self.program.clone(), Some(self.git_desc.clone()));
+        if reset_unknown {
+            metadata = metadata.with_unknown_reset();
+        }
         {
-            let json = serde_json::to_string(&metadata)?;
-            self.file.write_all(json.as_bytes())
+            let json = serde_json::to_vec(&metadata)?;
+            container::write_header(&mut self.file)
+                .and_then(|_| container::write_record(&mut self.file, RecordKind::Metadata, &json))
         }
         .map_err(SinkError::DrainIOError)?;
 
@@ -110,15 +208,24 @@ impl FileSink {
 }
 
 impl Sink for FileSink {
-    fn drain(&mut self, data: TraceData, _: api::EventChunk) -> Result<(), SinkError> {
-        let json = serde_json::to_string(&data)?;
-        self.file
-            .write_all(json.as_bytes())
+    fn drain(&mut self, packets: Arc<Packets>) -> Result<(), SinkError> {
+        // Raw records precede the packets decoded from them.
+        if let Some(raw) = packets.raw().filter(|raw| !raw.is_empty()) {
+            container::write_record(&mut self.file, RecordKind::Raw, raw)
+                .map_err(SinkError::DrainIOError)?;
+        }
+
+        let payload = bincode::serialize(packets.data())?;
+        container::write_record(&mut self.file, RecordKind::Packets, &payload)
             .map_err(SinkError::DrainIOError)
     }
 
+    fn finalize(&mut self) -> Result<(), SinkError> {
+        self.file.finish().map_err(SinkError::DrainIOError)
+    }
+
     fn describe(&self) -> String {
-        format!("file sink: {:?}", self.file)
+        format!("file sink: {:?}", self.file.file())
     }
 }
 
@@ -140,7 +247,7 @@ fn find_git_repo(mut path: PathBuf) -> Result<Repository, SinkError> {
     }
 }
 
-/// ls `*.trace` in given path.
+/// ls `*.trace` and `*.trace.zst` in given path.
 // TODO move to Source::file?
 pub fn find_trace_files(path: PathBuf) -> Result<impl Iterator<Item = PathBuf>, SinkError> {
     Ok(fs::read_dir(path)
@@ -149,14 +256,12 @@ pub fn find_trace_files(path: PathBuf) -> Result<impl Iterator<Item = PathBuf>,
         })?
         // we only care about files we can access
         .map(|entry| entry.unwrap())
-        // grep *.trace
+        // grep *.trace, *.trace.zst
         .filter_map(|entry| {
+            let name = entry.file_name();
+            let name = name.to_str().unwrap();
             if entry.file_type().unwrap().is_file()
-                && entry
-                    .file_name()
-                    .to_str()
-                    .unwrap()
-                    .ends_with(TRACE_FILE_EXT)
+                && (name.ends_with(TRACE_FILE_EXT) || name.ends_with(COMPRESSED_TRACE_FILE_EXT))
             {
                 Some(entry.path())
             } else {
@@ -1,8 +1,7 @@
-use crate::sinks::{Sink, SinkError};
-use crate::TraceData;
+use crate::sinks::{Packets, Sink, SinkError};
 
-use rtic_scope_api as api;
 use std::io::Write;
+use std::sync::Arc;
 
 pub struct FrontendSink {
     socket: std::os::unix::net::UnixStream,
@@ -15,14 +14,20 @@ impl FrontendSink {
 }
 
 impl Sink for FrontendSink {
-    fn drain(&mut self, _: TraceData, chunk: api::EventChunk) -> Result<(), SinkError> {
-        let json = serde_json::to_string(&chunk)?;
+    fn drain(&mut self, packets: Arc<Packets>) -> Result<(), SinkError> {
+        let json = serde_json::to_string(packets.chunk())?;
 
         self.socket
             .write_all(json.as_bytes())
             .map_err(SinkError::DrainIOError)
     }
 
+    /// Frontends only visualize the trace, which is recorded
+    /// regardless.
+    fn lossy(&self) -> bool {
+        true
+    }
+
     fn describe(&self) -> String {
         format!("frontend using socket {:?}", self.socket)
     }
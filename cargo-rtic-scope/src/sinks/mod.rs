@@ -1,6 +1,10 @@
 use crate::diag;
+use crate::recovery::Metadata;
 use crate::TraceData;
 
+use std::sync::Arc;
+
+use once_cell::sync::OnceCell;
 use rtic_scope_api as api;
 use thiserror::Error;
 
@@ -18,12 +22,18 @@ pub enum SinkError {
     GitError(#[from] git2::Error),
     #[error("Failed to serialize trace data: {0}")]
     DrainSerError(#[from] serde_json::Error),
+    #[error("Failed to encode trace data: {0}")]
+    DrainEncodeError(#[from] bincode::Error),
     #[error("Failed to drain trace data on I/O: {0}")]
     DrainIOError(#[source] std::io::Error),
     #[error("Failed to reset target device: {0}")]
     ResetError(#[from] probe_rs::Error),
     #[error("Failed to setup sink because the source failed: {0}")]
     SourceError(#[from] crate::sources::SourceError),
+    #[error("Trigger task-entered={0} refers to no known task; known tasks are: {}", .1.join(", "))]
+    UnknownTriggerTask(String, Vec<String>),
+    #[error("Trigger overrun can never fire: the manifest declares no timing constraints")]
+    UnconstrainedTrigger,
 }
 
 impl diag::DiagnosableError for SinkError {}
@@ -34,7 +44,76 @@ pub use file::FileSink;
 mod frontend;
 pub use frontend::FrontendSink;
 
-pub trait Sink {
-    fn drain(&mut self, data: TraceData, chunk: api::EventChunk) -> Result<(), SinkError>;
+mod chrome;
+pub use chrome::ChromeSink;
+
+mod vcd;
+pub use vcd::VcdSink;
+
+mod trigger;
+pub use trigger::{Trigger, TriggeredSink};
+
+mod worker;
+pub use worker::{send_or_halt, SinkWorker};
+
+/// A packet group read from the source, as forwarded to the sinks. A
+/// packet group is shared between the session analysis and all sinks;
+/// the RTIC events of the packets are mapped once, by whichever of them
+/// requests the events first. As the analysis requests the events of
+/// every packet group, mapping is moved off the main loop rather than
+/// avoided.
+pub struct Packets {
+    data: TraceData,
+    raw: Option<Vec<u8>>,
+    metadata: Arc<Metadata>,
+    chunk: OnceCell<api::EventChunk>,
+}
+
+impl Packets {
+    pub fn new(data: TraceData, raw: Option<Vec<u8>>, metadata: Arc<Metadata>) -> Self {
+        Self {
+            data,
+            raw,
+            metadata,
+            chunk: OnceCell::new(),
+        }
+    }
+
+    /// The decoded trace packets.
+    pub fn data(&self) -> &TraceData {
+        &self.data
+    }
+
+    /// The raw trace stream bytes the packets were decoded from, if the
+    /// source records them.
+    pub fn raw(&self) -> Option<&[u8]> {
+        self.raw.as_deref()
+    }
+
+    /// The RTIC events of the packets, mapped on first use.
+    pub fn chunk(&self) -> &api::EventChunk {
+        self.chunk
+            .get_or_init(|| self.metadata.build_event_chunk(&self.data))
+    }
+}
+
+/// A consumer of trace data. Each sink is drained on a dedicated thread,
+/// see [SinkWorker], and must thus be [Send].
+pub trait Sink: Send {
+    fn drain(&mut self, packets: Arc<Packets>) -> Result<(), SinkError>;
+
+    /// Whether packet groups may be dropped for the sink if it cannot
+    /// keep up with a live trace session. Sinks that record the trace
+    /// must not drop packet groups, lest the recording silently
+    /// contains gaps.
+    fn lossy(&self) -> bool {
+        false
+    }
+
+    /// Called once after the last drain, before the sink is dropped.
+    fn finalize(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
     fn describe(&self) -> String;
 }
@@ -0,0 +1,242 @@
+use crate::analysis::{self, ConstraintChecker, TaskTracker};
+use crate::log;
+use crate::manifest::TaskConstraints;
+use crate::recovery::Metadata;
+use crate::sinks::{Packets, Sink, SinkError};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+
+use itm_decode::{cortex_m::Exception, cortex_m::VectActive, ExceptionAction, TracePacket};
+use rtic_scope_api::{EventType, TaskAction};
+
+/// A condition that starts the capture of a [TriggeredSink].
+#[derive(Debug, Clone)]
+pub enum Trigger {
+    /// The given task is entered.
+    TaskEntered(String),
+    /// Any task violates a timing constraint declared in the manifest.
+    Overrun,
+    /// The ITM buffer overflowed.
+    Overflow,
+    /// The target entered the HardFault exception handler.
+    HardFault,
+}
+
+impl std::str::FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overrun" => Ok(Self::Overrun),
+            "overflow" => Ok(Self::Overflow),
+            "hardfault" => Ok(Self::HardFault),
+            s => match s.strip_prefix("task-entered=") {
+                Some(task) if !task.is_empty() => Ok(Self::TaskEntered(task.to_string())),
+                _ => Err(format!(
+                    "unknown trigger {}; expected task-entered=<task>, overrun, overflow, or hardfault",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl Trigger {
+    /// Verifies that the trigger can fire for a target with the given
+    /// tasks and timing constraints.
+    pub fn validate(
+        &self,
+        task_names: &[String],
+        constraints: &BTreeMap<String, TaskConstraints>,
+    ) -> Result<(), SinkError> {
+        match self {
+            Self::TaskEntered(name) if !task_names.iter().any(|t| analysis::refers_to(name, t)) => {
+                Err(SinkError::UnknownTriggerTask(
+                    name.clone(),
+                    task_names.to_vec(),
+                ))
+            }
+            Self::Overrun if constraints.is_empty() => Err(SinkError::UnconstrainedTrigger),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    /// Waiting for a trigger; packet groups are held in the pre-trigger
+    /// buffer.
+    Armed,
+    /// A trigger fired; the given number of packet groups remain to be
+    /// forwarded before the sink is armed again.
+    Capturing(usize),
+}
+
+/// Holds the trace stream in a ring buffer and only forwards it to the
+/// wrapped sink once a trigger fires. The `pre` packet groups preceding
+/// the trigger and the `post` packet groups following it are forwarded.
+/// A trigger that fires while capturing extends the capture. Once the
+/// capture ends the sink is armed anew.
+pub struct TriggeredSink {
+    inner: Box<dyn Sink>,
+    triggers: Vec<Trigger>,
+    pre: usize,
+    post: usize,
+    buffer: VecDeque<Arc<Packets>>,
+    state: State,
+    tracker: TaskTracker,
+    checker: ConstraintChecker,
+    fired: usize,
+}
+
+impl TriggeredSink {
+    pub fn new(
+        inner: Box<dyn Sink>,
+        triggers: Vec<Trigger>,
+        pre: usize,
+        post: usize,
+        metadata: &Metadata,
+    ) -> Self {
+        Self {
+            inner,
+            triggers,
+            pre,
+            post,
+            buffer: VecDeque::with_capacity(pre + 1),
+            state: State::Armed,
+            tracker: TaskTracker::default(),
+            checker: ConstraintChecker::new(metadata.constraints().clone()),
+            fired: 0,
+        }
+    }
+
+    /// Returns the first trigger that fires on the given packet group,
+    /// if any.
+    fn fires(&mut self, packets: &Packets) -> Option<&Trigger> {
+        let chunk = packets.chunk();
+        let task_events = self.tracker.update(chunk);
+        let overrun = !self.checker.check(&task_events).is_empty();
+
+        self.triggers.iter().find(|trigger| match trigger {
+            Trigger::TaskEntered(name) => chunk.events.iter().any(|event| match event {
+                EventType::Task {
+                    name: task,
+                    action: TaskAction::Entered,
+                } => analysis::refers_to(name, task),
+                _ => false,
+            }),
+            Trigger::Overrun => overrun,
+            Trigger::Overflow => chunk
+                .events
+                .iter()
+                .any(|event| matches!(event, EventType::Overflow)),
+            Trigger::HardFault => packets.data().packets.iter().any(|packet| {
+                matches!(
+                    packet,
+                    TracePacket::ExceptionTrace {
+                        exception: VectActive::Exception(Exception::HardFault),
+                        action: ExceptionAction::Entered,
+                    }
+                )
+            }),
+        })
+    }
+
+    /// Captures the given number of following packet groups, re-arming
+    /// the sink if there are none.
+    fn capture(remaining: usize) -> State {
+        match remaining {
+            0 => State::Armed,
+            n => State::Capturing(n),
+        }
+    }
+
+    fn forward(&mut self, packets: Arc<Packets>) -> Result<(), SinkError> {
+        self.inner.drain(packets)
+    }
+}
+
+impl Sink for TriggeredSink {
+    fn drain(&mut self, packets: Arc<Packets>) -> Result<(), SinkError> {
+        let trigger = self.fires(&packets).cloned();
+
+        if let Some(trigger) = &trigger {
+            self.fired += 1;
+            log::status(
+                "Triggered",
+                format!("on {:?} at {}", trigger, packets.chunk().timestamp.ts),
+            );
+        }
+
+        match (self.state, trigger) {
+            (State::Armed, None) => {
+                self.buffer.push_back(packets);
+                if self.buffer.len() > self.pre {
+                    self.buffer.pop_front();
+                }
+            }
+            (State::Armed, Some(_)) => {
+                while let Some(pre) = self.buffer.pop_front() {
+                    self.forward(pre)?;
+                }
+                self.forward(packets)?;
+                self.state = Self::capture(self.post);
+            }
+            (State::Capturing(_), Some(_)) => {
+                self.forward(packets)?;
+                self.state = Self::capture(self.post);
+            }
+            (State::Capturing(remaining), None) => {
+                self.forward(packets)?;
+                self.state = Self::capture(remaining - 1);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<(), SinkError> {
+        self.inner.finalize()
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} (triggered {} time(s))",
+            self.inner.describe(),
+            self.fired
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        let tasks = vec!["app::tick".to_string(), "app::blink".to_string()];
+        let mut constraints = BTreeMap::new();
+
+        let trigger = |s: &str| s.parse::<Trigger>().unwrap();
+        assert!(trigger("task-entered=blink")
+            .validate(&tasks, &constraints)
+            .is_ok());
+        assert!(trigger("task-entered=app::tick")
+            .validate(&tasks, &constraints)
+            .is_ok());
+        assert!(matches!(
+            trigger("task-entered=blinky").validate(&tasks, &constraints),
+            Err(SinkError::UnknownTriggerTask(name, _)) if name == "blinky"
+        ));
+        assert!(matches!(
+            trigger("overrun").validate(&tasks, &constraints),
+            Err(SinkError::UnconstrainedTrigger)
+        ));
+        assert!(trigger("overflow").validate(&tasks, &constraints).is_ok());
+
+        constraints.insert("blink".to_string(), TaskConstraints::default());
+        assert!(trigger("overrun").validate(&tasks, &constraints).is_ok());
+        assert!("task-entered=".parse::<Trigger>().is_err());
+    }
+}
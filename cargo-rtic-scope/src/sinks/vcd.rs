@@ -0,0 +1,181 @@
+use crate::recovery::Metadata;
+use crate::sinks::{Packets, Sink, SinkError};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+use rtic_scope_api::{EventType, TaskAction};
+
+const FS_PER_S: u128 = 1_000_000_000_000_000;
+
+/// A single-bit VCD signal tracking whether a task is active.
+struct Signal {
+    id: String,
+    active: bool,
+}
+
+/// Exports the event stream as a Value Change Dump for waveform
+/// viewers such as GTKWave. Each task known by the resolve maps is
+/// given a wire that is high while the task executes. Timestamps are
+/// derived from the trace clock cycle count so that the waveform
+/// retains cycle accuracy.
+pub struct VcdSink {
+    writer: BufWriter<fs::File>,
+    signals: BTreeMap<String, Signal>,
+    freq: u128,
+    /// Femtoseconds per VCD time unit.
+    unit_fs: u128,
+    last_time: u128,
+}
+
+impl VcdSink {
+    pub fn new(file: fs::File, metadata: &Metadata) -> Result<Self, SinkError> {
+        let freq = metadata.freq().max(1) as u128;
+        let exp = timescale_exponent(freq);
+
+        let mut sink = Self {
+            writer: BufWriter::new(file),
+            signals: metadata
+                .task_names()
+                .into_iter()
+                .enumerate()
+                .map(|(i, name)| {
+                    (
+                        name,
+                        Signal {
+                            id: identifier(i),
+                            active: false,
+                        },
+                    )
+                })
+                .collect(),
+            freq,
+            unit_fs: 10u128.pow(exp),
+            last_time: 0,
+        };
+        sink.write_header(metadata, exp).map_err(|e| {
+            SinkError::SetupIOError(Some("Failed to write VCD header".to_string()), e)
+        })?;
+
+        Ok(sink)
+    }
+
+    fn write_header(&mut self, metadata: &Metadata, exp: u32) -> std::io::Result<()> {
+        let w = &mut self.writer;
+        writeln!(w, "$date {} $end", metadata.reset_timestamp())?;
+        writeln!(
+            w,
+            "$version cargo-rtic-scope {} $end",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        if !metadata.comment().is_empty() {
+            writeln!(w, "$comment {} $end", metadata.comment())?;
+        }
+        writeln!(
+            w,
+            "$timescale {} {} $end",
+            10u32.pow(exp % 3),
+            ["fs", "ps", "ns", "us", "ms", "s"][(exp / 3) as usize]
+        )?;
+        writeln!(w, "$scope module tasks $end")?;
+        for (name, signal) in self.signals.iter() {
+            writeln!(w, "$var wire 1 {} {} $end", signal.id, name)?;
+        }
+        writeln!(w, "$upscope $end")?;
+        writeln!(w, "$enddefinitions $end")?;
+
+        writeln!(w, "#0")?;
+        writeln!(w, "$dumpvars")?;
+        for signal in self.signals.values() {
+            writeln!(w, "0{}", signal.id)?;
+        }
+        writeln!(w, "$end")
+    }
+
+    /// Converts a trace clock cycle count to VCD time units.
+    fn cycles_to_time(&self, cycles: u128) -> u128 {
+        (cycles * (FS_PER_S / self.unit_fs) + self.freq / 2) / self.freq
+    }
+}
+
+impl Sink for VcdSink {
+    fn drain(&mut self, packets: Arc<Packets>) -> Result<(), SinkError> {
+        let timestamp = &packets.data().timestamp;
+        let cycles = timestamp.base.unwrap_or(0) + timestamp.delta.unwrap_or(0);
+        // VCD time must be monotonic; diverged timestamps are clamped.
+        let time = self.cycles_to_time(cycles as u128).max(self.last_time);
+
+        let mut changes = vec![];
+        for event in packets.chunk().events.iter() {
+            if let EventType::Task { name, action } = event {
+                let signal = match self.signals.get_mut(name) {
+                    Some(signal) => signal,
+                    None => continue,
+                };
+                let active = !matches!(action, TaskAction::Exited);
+                if signal.active != active {
+                    signal.active = active;
+                    changes.push(format!("{}{}", active as u8, signal.id));
+                }
+            }
+        }
+
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        if time != self.last_time {
+            writeln!(self.writer, "#{}", time).map_err(SinkError::DrainIOError)?;
+            self.last_time = time;
+        }
+        for change in changes {
+            writeln!(self.writer, "{}", change).map_err(SinkError::DrainIOError)?;
+        }
+
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<(), SinkError> {
+        self.writer.flush().map_err(SinkError::DrainIOError)
+    }
+
+    fn describe(&self) -> String {
+        format!("VCD sink: {:?}", self.writer.get_ref())
+    }
+}
+
+/// Finds the coarsest timescale, as a power of ten in femtoseconds,
+/// that represents a trace clock period of the given frequency
+/// exactly. If no exact representation exists, picoseconds are used.
+fn timescale_exponent(freq: u128) -> u32 {
+    if FS_PER_S % freq != 0 {
+        return 3;
+    }
+
+    let period_fs = FS_PER_S / freq;
+    (0..=15)
+        .rev()
+        .find(|exp| period_fs % 10u128.pow(*exp) == 0)
+        .unwrap_or(0)
+}
+
+/// Generates a short VCD identifier code from the printable ASCII
+/// range.
+fn identifier(mut i: usize) -> String {
+    const FIRST: u8 = b'!';
+    const RADIX: usize = (b'~' - b'!' + 1) as usize;
+
+    let mut id = String::new();
+    loop {
+        id.push((FIRST + (i % RADIX) as u8) as char);
+        i /= RADIX;
+        if i == 0 {
+            break;
+        }
+        i -= 1;
+    }
+
+    id
+}
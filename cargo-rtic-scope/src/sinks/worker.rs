@@ -0,0 +1,219 @@
+use crate::log;
+use crate::sinks::{Packets, Sink};
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    mpsc, Arc,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of packet groups that may be queued for a sink before further
+/// packet groups are dropped.
+const QUEUE_LEN: usize = 4096;
+
+/// Interval at which a full queue is polled for room.
+const FULL_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Queues `item` on a bounded queue. If the queue is full, waits for
+/// room until `halt` is set, in which case [mpsc::TrySendError::Full] is
+/// returned. On success, returns for how long the queue was full, if it
+/// was.
+pub fn send_or_halt<T>(
+    tx: &mpsc::SyncSender<T>,
+    item: T,
+    halt: &AtomicBool,
+) -> Result<Option<Duration>, mpsc::TrySendError<T>> {
+    let mut item = match tx.try_send(item) {
+        Ok(()) => return Ok(None),
+        Err(mpsc::TrySendError::Full(item)) => item,
+        Err(e) => return Err(e),
+    };
+
+    let start = Instant::now();
+    loop {
+        if halt.load(Ordering::SeqCst) {
+            return Err(mpsc::TrySendError::Full(item));
+        }
+        thread::sleep(FULL_POLL_INTERVAL);
+        item = match tx.try_send(item) {
+            Ok(()) => return Ok(Some(start.elapsed())),
+            Err(mpsc::TrySendError::Full(item)) => item,
+            Err(e) => return Err(e),
+        };
+    }
+}
+
+/// Drains a [Sink] on a dedicated thread so that a slow sink does not
+/// stall the reading of the source or the draining of other sinks.
+/// Packet groups are queued for the sink. If the queue is full, the
+/// packet group is either dropped for that sink, if the worker is lossy,
+/// or queued once the sink has caught up. Meanwhile, no further packet
+/// groups are forwarded: the time spent waiting is reported in
+/// [SinkWorker::stalled]. A packet group that is still waiting when the
+/// session is halted is dropped.
+pub struct SinkWorker {
+    tx: mpsc::SyncSender<Arc<Packets>>,
+    lossy: bool,
+    halt: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+    depth: Arc<AtomicUsize>,
+    dropped: usize,
+    stalled: Duration,
+    description: String,
+}
+
+impl SinkWorker {
+    pub fn spawn(mut sink: Box<dyn Sink>, lossy: bool, halt: Arc<AtomicBool>) -> Self {
+        let (tx, rx) = mpsc::sync_channel::<Arc<Packets>>(QUEUE_LEN);
+        let depth = Arc::new(AtomicUsize::new(0));
+        let description = sink.describe();
+
+        let handle = {
+            let depth = depth.clone();
+            thread::spawn(move || {
+                // Drain until the queue is closed. Upon error the sink
+                // is considered broken and the queue is dropped.
+                for packets in rx {
+                    depth.fetch_sub(1, Ordering::SeqCst);
+
+                    if let Err(e) = sink.drain(packets) {
+                        log::err(format!(
+                            "failed to drain trace packets to {}: {:?}",
+                            sink.describe(),
+                            e
+                        ));
+                        return;
+                    }
+                }
+
+                // flush any buffered output and close frontend sockets
+                if let Err(e) = sink.finalize() {
+                    log::err(format!("failed to finalize {}: {:?}", sink.describe(), e));
+                }
+            })
+        };
+
+        Self {
+            tx,
+            lossy,
+            halt,
+            handle,
+            depth,
+            dropped: 0,
+            stalled: Duration::ZERO,
+            description,
+        }
+    }
+
+    /// Queues a packet group for the sink. Returns `false` if the sink
+    /// has broken.
+    pub fn send(&mut self, packets: Arc<Packets>) -> bool {
+        // Count before sending so that the worker never observes a
+        // depth below zero.
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        let sent = if self.lossy {
+            match self.tx.try_send(packets) {
+                Ok(()) => true,
+                Err(mpsc::TrySendError::Full(_)) => {
+                    self.dropped += 1;
+                    self.depth.fetch_sub(1, Ordering::SeqCst);
+                    return true;
+                }
+                Err(mpsc::TrySendError::Disconnected(_)) => false,
+            }
+        } else {
+            match send_or_halt(&self.tx, packets, &self.halt) {
+                Ok(None) => true,
+                Ok(Some(stall)) => {
+                    if self.stalled == Duration::ZERO {
+                        log::warn(format!(
+                            "{} cannot keep up; trace data is not forwarded until it catches up",
+                            self.description
+                        ));
+                    }
+                    self.stalled += stall;
+                    true
+                }
+                Err(mpsc::TrySendError::Full(_)) => {
+                    self.dropped += 1;
+                    self.depth.fetch_sub(1, Ordering::SeqCst);
+                    return true;
+                }
+                Err(mpsc::TrySendError::Disconnected(_)) => false,
+            }
+        };
+
+        if !sent {
+            self.depth.fetch_sub(1, Ordering::SeqCst);
+        }
+        sent
+    }
+
+    /// Number of packet groups queued for the sink.
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    /// Number of packet groups dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Time spent waiting for the sink to catch up with a full queue.
+    pub fn stalled(&self) -> Duration {
+        self.stalled
+    }
+
+    pub fn describe(&self) -> &str {
+        &self.description
+    }
+
+    /// Closes the queue and waits for the sink to drain it and finalize.
+    pub fn finish(self) {
+        drop(self.tx);
+        if self.handle.join().is_err() {
+            log::err(format!("sink {} panicked", self.description));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_or_halt_on_full_queue() {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let halt = Arc::new(AtomicBool::new(false));
+        assert!(matches!(send_or_halt(&tx, 1, &halt), Ok(None)));
+
+        // Waits for room...
+        let receiver = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(rx.recv(), Ok(1));
+            rx
+        });
+        assert!(matches!(send_or_halt(&tx, 2, &halt), Ok(Some(_))));
+        let rx = receiver.join().unwrap();
+
+        // ...until halted.
+        let h = halt.clone();
+        let halter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            h.store(true, Ordering::SeqCst);
+        });
+        assert!(matches!(
+            send_or_halt(&tx, 3, &halt),
+            Err(mpsc::TrySendError::Full(3))
+        ));
+        halter.join().unwrap();
+        assert_eq!(rx.try_recv(), Ok(2));
+
+        drop(rx);
+        assert!(matches!(
+            send_or_halt(&tx, 4, &halt),
+            Err(mpsc::TrySendError::Disconnected(4))
+        ));
+    }
+}
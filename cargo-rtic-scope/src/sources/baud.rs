@@ -0,0 +1,147 @@
+use crate::sources::{tty, SourceError};
+
+use std::fmt;
+use std::io::{ErrorKind, Read};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+use itm_decode::{Decoder, DecoderOptions, TracePacket};
+use nix::poll::{poll, PollFd, PollFlags};
+
+/// Baud rates tried if no candidates are given: common SWO rates, most
+/// of which are generated exactly by typical FTDI and CP210x adapters.
+pub const DEFAULT_CANDIDATES: &[u32] = &[
+    115200, 230400, 460800, 921600, 1000000, 1500000, 2000000, 3000000, 4000000, 6000000, 12000000,
+];
+
+/// How long the stream is sampled at each candidate rate.
+pub const SAMPLE_WINDOW: Duration = Duration::from_millis(500);
+
+/// A candidate rate must yield at least this many packets, well-formed
+/// or not, to be considered at all. Line noise at a wrong rate may
+/// decode to a few well-formed packets by chance.
+const MIN_PACKETS: usize = 16;
+
+/// The outcome of decoding the trace stream sampled at a candidate
+/// baud rate.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub baud: u32,
+    pub bytes: usize,
+    pub packets: usize,
+    pub malformed: usize,
+    pub syncs: usize,
+}
+
+impl Candidate {
+    /// Decodes `bytes` sampled at the given `baud` rate.
+    pub fn assess(baud: u32, bytes: &[u8]) -> Self {
+        let mut candidate = Self {
+            baud,
+            bytes: bytes.len(),
+            packets: 0,
+            malformed: 0,
+            syncs: 0,
+        };
+
+        let mut decoder = Decoder::new(DecoderOptions::default());
+        decoder.push(bytes);
+        while let Some(data) = decoder.pull_with_timestamp() {
+            candidate.packets += data.packets.len();
+            candidate.malformed += data.malformed_packets.len();
+            candidate.syncs += data
+                .packets
+                .iter()
+                .filter(|packet| matches!(packet, TracePacket::Sync))
+                .count();
+        }
+
+        candidate
+    }
+
+    /// Ratio of well-formed packets to all packets decoded, or zero if
+    /// too few packets were decoded to tell.
+    pub fn score(&self) -> f64 {
+        let total = self.packets + self.malformed;
+        if total < MIN_PACKETS {
+            0.0
+        } else {
+            self.packets as f64 / total as f64
+        }
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>9} baud: {:>5.1}% well-formed ({} packets, {} malformed, {} syncs, {} bytes)",
+            self.baud,
+            self.score() * 100.0,
+            self.packets,
+            self.malformed,
+            self.syncs,
+            self.bytes
+        )
+    }
+}
+
+/// Reads from `device` at the given `baud` rate for [SAMPLE_WINDOW].
+pub fn sample(device: &str, baud: u32) -> Result<Vec<u8>, SourceError> {
+    let mut file = tty::configure(device, baud)?;
+    let mut bytes = vec![];
+    let mut buf = [0; 4096];
+    let deadline = Instant::now() + SAMPLE_WINDOW;
+
+    // The device blocks until the first byte arrives, so poll for data
+    // lest we wait forever on a silent line.
+    let mut fds = [PollFd::new(file.as_raw_fd(), PollFlags::POLLIN)];
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match poll(&mut fds, left.as_millis() as i32) {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(e) => {
+                return Err(SourceError::SetupError(format!(
+                    "Failed to poll {}: poll = {}",
+                    device, e
+                )))
+            }
+        }
+
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => bytes.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(SourceError::SetupIOError(e)),
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// Samples the trace stream on `device` at each of the `candidates`
+/// rates. Rates the device cannot generate are skipped. Returns the
+/// assessed candidates, the most plausible first: ties in score are
+/// broken by the number of sync packets, which only decode at the right
+/// rate, and then by the number of packets. The target must already be
+/// emitting trace data.
+pub fn detect(device: &str, candidates: &[u32]) -> Result<Vec<Candidate>, SourceError> {
+    let mut assessed = vec![];
+    for &baud in candidates {
+        match sample(device, baud) {
+            Ok(bytes) => assessed.push(Candidate::assess(baud, &bytes)),
+            Err(SourceError::BaudRateRejected(..)) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    let rank = |c: &Candidate| (c.score(), c.syncs, c.packets);
+    assessed.sort_by(|a, b| {
+        rank(b)
+            .partial_cmp(&rank(a))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    Ok(assessed)
+}
@@ -1,24 +1,87 @@
+use crate::container::{self, RecordKind};
+use crate::log;
 use crate::recovery::Metadata;
 use crate::sources::{BufferStatus, Source, SourceError};
 use crate::TraceData;
 
 use std::fs;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader, ErrorKind};
 
-/// Something data is deserialized from. Always a file.
+use itm_decode::{Decoder, TracePacket};
+use serde::Serialize;
+
+/// On-disk format of a trace file.
+enum Format {
+    /// Concatenated JSON objects, as written by older versions.
+    Json,
+    /// Versioned binary container, see [crate::container].
+    Binary {
+        version: u16,
+        /// Bytes of the file covered by intact records read thus far.
+        offset: u64,
+        /// Total size of the file, if known. Unknown for compressed
+        /// files.
+        len: Option<u64>,
+        /// Whether a damaged record has been encountered.
+        exhausted: bool,
+    },
+}
+
+/// State for decoding trace packets anew from recorded raw bytes.
+struct Redecode {
+    decoder: Decoder,
+    /// Whether any raw record has been read.
+    raw_seen: bool,
+}
+
+/// Something data is deserialized from. Always a file, optionally
+/// zstd-compressed.
 pub struct FileSource {
-    reader: BufReader<fs::File>,
+    reader: Box<dyn BufRead + Send>,
+    file_name: String,
     metadata: Metadata,
+    format: Format,
+    redecode: Option<Redecode>,
 }
 
 impl FileSource {
     pub fn new(fd: fs::File) -> Result<Self, SourceError> {
+        let file_name = format!("{:?}", fd);
         let mut reader = BufReader::new(fd);
-        let metadata = {
+
+        // Transparently decompress compressed traces. The size of the
+        // decompressed stream is then unknown.
+        let (mut reader, len): (Box<dyn BufRead + Send>, _) =
+            if container::is_compressed(reader.fill_buf().map_err(SourceError::SetupIOError)?) {
+                let decoder =
+                    zstd::Decoder::with_buffer(reader).map_err(SourceError::SetupIOError)?;
+                (Box::new(BufReader::new(decoder)), None)
+            } else {
+                let len = reader
+                    .get_ref()
+                    .metadata()
+                    .map_err(SourceError::SetupIOError)?
+                    .len();
+                (Box::new(reader), Some(len))
+            };
+        let is_binary = container::has_magic(reader.fill_buf().map_err(SourceError::SetupIOError)?);
+
+        let (metadata, format) = if is_binary {
+            let (metadata, version, offset) = Self::read_binary_header(&mut reader)?;
+            (
+                metadata,
+                Format::Binary {
+                    version,
+                    offset,
+                    len,
+                    exhausted: false,
+                },
+            )
+        } else {
             let mut stream =
                 serde_json::Deserializer::from_reader(&mut reader).into_iter::<Metadata>();
             if let Some(Ok(metadata)) = stream.next() {
-                metadata
+                (metadata, Format::Json)
             } else {
                 return Err(SourceError::SetupError(
                     "Failed to deserialize metadata header".to_string(),
@@ -26,7 +89,48 @@ impl FileSource {
             }
         };
 
-        Ok(Self { reader, metadata })
+        Ok(Self {
+            reader,
+            file_name,
+            metadata,
+            format,
+            redecode: None,
+        })
+    }
+
+    /// Ignores the recorded trace packets and instead decodes the
+    /// recorded raw trace stream with the current decoder, using the
+    /// options the trace was captured with.
+    pub fn redecode(&mut self) {
+        self.redecode = Some(Redecode {
+            decoder: self.metadata.decode_options().decoder(),
+            raw_seen: false,
+        });
+    }
+
+    /// Reads the file header and metadata record. Returns the metadata,
+    /// the format version, and the number of bytes read.
+    fn read_binary_header(reader: &mut dyn BufRead) -> Result<(Metadata, u16, u64), SourceError> {
+        let version = container::read_header(reader).map_err(SourceError::SetupIOError)?;
+        if version > container::FORMAT_VERSION {
+            return Err(SourceError::UnsupportedFormatVersion(version));
+        }
+
+        match container::read_record(reader, version).map_err(SourceError::SetupIOError)? {
+            Some((RecordKind::Metadata, payload)) => {
+                let metadata = serde_json::from_slice(&payload).map_err(|e| {
+                    SourceError::SetupError(format!("Failed to deserialize metadata header: {}", e))
+                })?;
+                let offset = (container::MAGIC.len() + 2) as u64
+                    + container::record_overhead(version)
+                    + payload.len() as u64;
+
+                Ok((metadata, version, offset))
+            }
+            _ => Err(SourceError::SetupError(
+                "Trace file does not start with a metadata header".to_string(),
+            )),
+        }
     }
 
     pub fn metadata(&self) -> Metadata {
@@ -34,16 +138,169 @@ impl FileSource {
     }
 }
 
+/// Summary of the content of a trace file.
+#[derive(Default, Serialize)]
+pub struct TraceSummary {
+    /// Seconds from target reset to the last timestamp in the trace.
+    pub duration: f64,
+    pub packets: usize,
+    pub malformed: usize,
+    pub overflows: usize,
+}
+
+impl FileSource {
+    /// Reads the remainder of the trace and summarizes its content.
+    pub fn summarize(self) -> Result<TraceSummary, SourceError> {
+        let freq = self.metadata.freq().max(1) as f64;
+        let mut summary = TraceSummary::default();
+
+        for data in self {
+            let data = data?;
+            let cycles = data.timestamp.base.unwrap_or(0) + data.timestamp.delta.unwrap_or(0);
+
+            summary.duration = summary.duration.max(cycles as f64 / freq);
+            summary.packets += data.packets_consumed;
+            summary.malformed += data.malformed_packets.len();
+            summary.overflows += data
+                .packets
+                .iter()
+                .filter(|packet| matches!(packet, TracePacket::Overflow))
+                .count();
+        }
+
+        Ok(summary)
+    }
+}
+
+impl Format {
+    /// Number of bytes discarded from the end of the file because of a
+    /// truncated or corrupt record, once encountered. Unknown for
+    /// compressed files.
+    fn discarded(&self) -> Option<u64> {
+        match *self {
+            Format::Binary {
+                offset,
+                len: Some(len),
+                exhausted: true,
+                ..
+            } => Some(len.saturating_sub(offset)),
+            _ => None,
+        }
+    }
+
+    /// Reads the next record of the `wanted` kind from a binary trace
+    /// file, skipping all others.
+    fn next_record(
+        &mut self,
+        reader: &mut dyn BufRead,
+        wanted: RecordKind,
+    ) -> Option<Result<Vec<u8>, SourceError>> {
+        let (version, offset, len, exhausted) = match self {
+            Format::Binary {
+                version,
+                offset,
+                len,
+                exhausted,
+            } => (*version, offset, *len, exhausted),
+            Format::Json => unreachable!("JSON trace files do not contain records"),
+        };
+
+        loop {
+            if *exhausted {
+                return None;
+            }
+
+            match container::read_record(reader, version) {
+                Ok(Some((kind, payload))) => {
+                    *offset += container::record_overhead(version) + payload.len() as u64;
+                    if kind == wanted {
+                        return Some(Ok(payload));
+                    }
+                    // Records of other kinds, including those unknown
+                    // to us, are skipped.
+                }
+                Ok(None) => return None,
+                // The recording was interrupted or the file is
+                // damaged. Everything up to this record is intact. A
+                // damaged compressed stream may surface as any kind of
+                // error from the decompressor.
+                Err(e)
+                    if len.is_none()
+                        || matches!(
+                            e.kind(),
+                            ErrorKind::UnexpectedEof | ErrorKind::InvalidData
+                        ) =>
+                {
+                    *exhausted = true;
+                    let offset = *offset;
+                    log::warn(format!(
+                        "trace file ends with a truncated or corrupt record ({}); discarded {}",
+                        e,
+                        match self.discarded() {
+                            Some(discarded) => format!("the last {} bytes", discarded),
+                            None => format!(
+                                "the remainder of the compressed stream after {} bytes",
+                                offset
+                            ),
+                        }
+                    ));
+                    return None;
+                }
+                Err(e) => return Some(Err(SourceError::IterIOError(e))),
+            }
+        }
+    }
+}
+
 impl Iterator for FileSource {
     type Item = Result<TraceData, SourceError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut stream =
-            serde_json::Deserializer::from_reader(&mut self.reader).into_iter::<TraceData>();
-        match stream.next() {
-            Some(Ok(data)) => Some(Ok(data)),
-            Some(Err(e)) => Some(Err(SourceError::IterDeserError(e))),
-            None => None,
+        if let Some(ref mut redecode) = self.redecode {
+            if let Format::Json = self.format {
+                return if std::mem::replace(&mut redecode.raw_seen, true) {
+                    None
+                } else {
+                    Some(Err(SourceError::NoRawData))
+                };
+            }
+
+            loop {
+                if let Some(packets) = redecode.decoder.pull_with_timestamp() {
+                    return Some(Ok(packets));
+                }
+
+                match self.format.next_record(&mut self.reader, RecordKind::Raw) {
+                    Some(Ok(bytes)) => {
+                        redecode.raw_seen = true;
+                        redecode.decoder.push(&bytes);
+                    }
+                    Some(Err(e)) => return Some(Err(e)),
+                    None if !redecode.raw_seen => {
+                        redecode.raw_seen = true;
+                        return Some(Err(SourceError::NoRawData));
+                    }
+                    None => return None,
+                }
+            }
+        }
+
+        match self.format {
+            Format::Json => {
+                let mut stream = serde_json::Deserializer::from_reader(&mut self.reader)
+                    .into_iter::<TraceData>();
+                match stream.next() {
+                    Some(Ok(data)) => Some(Ok(data)),
+                    Some(Err(e)) => Some(Err(SourceError::IterDeserError(e))),
+                    None => None,
+                }
+            }
+            Format::Binary { .. } => self
+                .format
+                .next_record(&mut self.reader, RecordKind::Packets)
+                .map(|payload| {
+                    payload.and_then(|p| bincode::deserialize(&p).map_err(|e| e.into()))
+                }),
         }
     }
 }
@@ -54,6 +311,92 @@ impl Source for FileSource {
     }
 
     fn describe(&self) -> String {
-        format!("file ({:?})", self.reader.get_ref())
+        format!("file ({})", self.file_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::DecodeOptions;
+
+    use std::io::{Seek, SeekFrom, Write};
+
+    const METADATA: &str = r#"{
+        "maps": { "exceptions": {}, "interrupts": {}, "sw_assocs": {} },
+        "manip": {
+            "pac_name": "stm32f4",
+            "pac_version": "0.13",
+            "pac_features": [],
+            "interrupt_path": "stm32f4::stm32f401::Interrupt",
+            "tpiu_freq": 16000000,
+            "tpiu_baud": 115200,
+            "dwt_enter_id": 1,
+            "dwt_exit_id": 2
+        },
+        "timestamp": "2021-11-01T12:00:00+01:00",
+        "freq": 16000000,
+        "comment": null
+    }"#;
+
+    /// A packet record holding a single instrumentation packet.
+    fn packets(payload: u8) -> Vec<u8> {
+        let mut decoder = DecodeOptions::CAPTURE.decoder();
+        decoder.push(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x09, payload, 0x10]);
+        let data = decoder.pull_with_timestamp().unwrap();
+
+        let mut record = vec![];
+        container::write_record(
+            &mut record,
+            RecordKind::Packets,
+            &bincode::serialize(&data).unwrap(),
+        )
+        .unwrap();
+        record
+    }
+
+    /// Writes a trace file with the given records following the
+    /// metadata record, and opens it.
+    fn open(records: &[u8]) -> FileSource {
+        let mut file = tempfile::tempfile().unwrap();
+        container::write_header(&mut file).unwrap();
+        container::write_record(&mut file, RecordKind::Metadata, METADATA.as_bytes()).unwrap();
+        file.write_all(records).unwrap();
+        file.sync_all().unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        FileSource::new(file).unwrap()
+    }
+
+    #[test]
+    fn intact() {
+        let mut source = open(&[packets(1), packets(2)].concat());
+
+        assert_eq!(source.by_ref().filter(Result::is_ok).count(), 2);
+        assert_eq!(source.format.discarded(), None);
+    }
+
+    #[test]
+    fn truncated_record() {
+        let mut records = [packets(1), packets(2)].concat();
+        records.truncate(records.len() - 3);
+        let discarded = packets(2).len() as u64 - 3;
+        let mut source = open(&records);
+
+        assert!(source.next().unwrap().is_ok());
+        assert!(source.next().is_none());
+        assert_eq!(source.format.discarded(), Some(discarded));
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let second = packets(2);
+        let discarded = second.len() as u64;
+        let mut records = [packets(1), second].concat();
+        *records.last_mut().unwrap() ^= 1;
+        let mut source = open(&records);
+
+        assert!(source.next().unwrap().is_ok());
+        assert!(source.next().is_none());
+        assert_eq!(source.format.discarded(), Some(discarded));
     }
 }
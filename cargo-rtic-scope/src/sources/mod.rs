@@ -1,8 +1,41 @@
 use crate::diag;
 use crate::TraceData;
 
+use std::sync::{atomic::AtomicBool, Arc};
+use std::time::Duration;
+
+use itm_decode::{Decoder, DecoderOptions};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+/// Options the trace stream is decoded with. Recorded in the metadata
+/// of a trace, so that its raw trace stream is decoded anew the same
+/// way it was when captured.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DecodeOptions {
+    /// See [itm_decode::DecoderOptions::only_gts].
+    pub only_gts: bool,
+}
+
+impl DecodeOptions {
+    /// Options all trace streams are captured with.
+    pub const CAPTURE: Self = Self { only_gts: false };
+
+    pub fn decoder(&self) -> Decoder {
+        Decoder::new(DecoderOptions {
+            only_gts: self.only_gts,
+        })
+    }
+}
+
+impl Default for DecodeOptions {
+    /// Traces recorded before the options were recorded were captured
+    /// with [DecodeOptions::CAPTURE].
+    fn default() -> Self {
+        Self::CAPTURE
+    }
+}
+
 #[derive(Debug)]
 pub enum BufferStatus {
     /// The given amount of bytes that are available in the buffer.
@@ -25,23 +58,98 @@ pub enum SourceError {
     SetupIOError(#[source] std::io::Error),
     #[error("Failed to setup source probe: {0}")]
     SetupProbeError(#[source] probe_rs::Error),
+    #[error("{0} rejected a baud rate of {1}: {2}")]
+    BaudRateRejected(String, u32, String),
+    #[error("No plausible SWO baud rate found on {0}")]
+    NoBaudRate(String),
+    #[error(
+        "Trace file was written with format version {0}, but only versions up to {} are supported",
+        crate::container::FORMAT_VERSION
+    )]
+    UnsupportedFormatVersion(u16),
     #[error("Failed to deserialize trace data from source: {0}")]
     IterDeserError(#[from] serde_json::Error),
+    #[error("Failed to decode trace data from source: {0}")]
+    IterDecodeError(#[from] bincode::Error),
     #[error("Failed to read trace data from file: {0}")]
     IterIOError(#[source] std::io::Error),
     #[error("Failed to read trace data from probe: {0}")]
     IterProbeError(#[source] probe_rs::Error),
     #[error("Failed to reset target device: {0}")]
     ResetError(#[source] probe_rs::Error),
+    #[error("Trace file contains no raw trace data to re-decode")]
+    NoRawData,
+    #[error("No trace data decoded from {0} for {:.1}s", .1.as_secs_f64())]
+    Stalled(String, Duration),
 }
 
-impl diag::DiagnosableError for SourceError {}
+impl diag::DiagnosableError for SourceError {
+    fn diagnose(&self) -> Vec<String> {
+        match self {
+            Self::UnsupportedFormatVersion(_) => vec![
+                "The trace file was recorded by a newer version of cargo-rtic-scope. Update cargo-rtic-scope to replay it.".to_string(),
+            ],
+            Self::BaudRateRejected(..) => vec![
+                "The baud rate is read from `tpiu_baud` in [package.metadata.rtic-scope] or --tpiu-baud, and must match the SWO output rate of the target.".to_string(),
+                "Serial adapters can only generate rates that evenly divide their base clock. Consult the adapter's datasheet for supported rates.".to_string(),
+            ],
+            Self::NoBaudRate(_) => vec![
+                "The target must emit trace data while the rates are sampled. Ensure that the firmware configures the TPIU for SWO output and that the SWO pin is connected to the serial adapter.".to_string(),
+                "The target may use a rate not among those sampled. Pass the rate derived from the TPIU prescaler to --baud-candidates.".to_string(),
+            ],
+            Self::NoRawData => vec![
+                "Raw trace data is only recorded if `cargo rtic-scope trace` is passed --record-raw.".to_string(),
+            ],
+            Self::Stalled(..) => vec![
+                "The SWO baud rate may not match the output rate of the target. Check `tpiu_freq` and `tpiu_baud` in [package.metadata.rtic-scope], or pass --detect-baud along with --serial.".to_string(),
+                "The SWO pin may not be enabled or connected. Ensure that the application calls the setup functions of cortex-m-rtic-trace, or pass --host-setup.".to_string(),
+                "The target may be halted: by --reset-halt, at a breakpoint, or in a fault handler. `cargo rtic-scope doctor` checks the tracing setup of a running target.".to_string(),
+                "An application that legitimately idles for longer without emitting trace data needs a larger --stall-timeout.".to_string(),
+            ],
+            _ => vec![],
+        }
+    }
+}
 
-pub trait Source: Iterator<Item = Result<TraceData, SourceError>> {
+/// A source of trace data. Sources are read on a dedicated thread and
+/// must thus be [Send].
+pub trait Source: Iterator<Item = Result<TraceData, SourceError>> + Send {
     fn reset_target(&mut self, _reset_halt: bool) -> Result<(), SourceError> {
         Ok(())
     }
 
+    /// Starts collecting the raw trace stream bytes that packets are
+    /// decoded from, if supported by the source.
+    fn record_raw(&mut self) {}
+
+    /// Returns the raw bytes collected since the last call, if
+    /// collection has been started via [Source::record_raw].
+    fn take_raw(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Ends the source once `halt` is set, if the source may otherwise
+    /// block indefinitely, e.g. while reconnecting.
+    fn halt_on(&mut self, _halt: Arc<AtomicBool>) {}
+
+    /// Demultiplexes the raw trace stream with the given [TpiuDemux],
+    /// for streams in which the TPIU formatter is enabled.
+    fn deframe(&mut self, _demux: TpiuDemux) -> Result<(), SourceError> {
+        Err(SourceError::SetupError(format!(
+            "{} does not carry a raw trace stream to deframe",
+            self.describe()
+        )))
+    }
+
+    /// Fails the source with [SourceError::Stalled] once no trace data
+    /// has been decoded for `timeout`, if supported by the source.
+    fn stall_after(&mut self, _timeout: Duration) -> Result<(), SourceError> {
+        Err(SourceError::SetupError(format!(
+            "{} does not support stall detection",
+            self.describe()
+        )))
+    }
+
     /// Reports the available bytes in the input buffer, if able.
     fn avail_buffer(&self) -> BufferStatus {
         BufferStatus::Unknown
@@ -51,7 +159,7 @@ pub trait Source: Iterator<Item = Result<TraceData, SourceError>> {
 }
 
 mod file;
-pub use file::FileSource;
+pub use file::{FileSource, TraceSummary};
 
 pub mod tty;
 pub use tty::TTYSource;
@@ -61,3 +169,18 @@ pub use probe::ProbeSource;
 
 mod raw_file;
 pub use raw_file::RawFileSource;
+
+mod pipe;
+pub use pipe::PipeSource;
+
+mod tcp;
+pub use tcp::TcpSource;
+
+mod tpiu;
+pub use tpiu::TpiuDemux;
+
+pub mod baud;
+
+mod stall;
+
+mod stream;
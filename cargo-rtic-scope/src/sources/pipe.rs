@@ -0,0 +1,73 @@
+use crate::sources::{stream::StreamDecoder, BufferStatus, Source, SourceError, TpiuDemux};
+use crate::TraceData;
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Something data is deserialized from. Either stdin or a named pipe
+/// (FIFO), over which the raw trace stream is piped from an external
+/// capture tool, such as sigrok.
+pub struct PipeSource {
+    name: String,
+    stream: StreamDecoder<Box<dyn Read + Send>>,
+}
+
+impl PipeSource {
+    /// Opens the given named pipe, or stdin if the path is `-`. Opening
+    /// a named pipe blocks until a writer has opened it.
+    pub fn open(path: &Path) -> Result<Self, SourceError> {
+        let (name, reader): (_, Box<dyn Read + Send>) = if path == Path::new("-") {
+            ("stdin".to_string(), Box::new(io::stdin()))
+        } else {
+            (
+                path.display().to_string(),
+                Box::new(
+                    fs::OpenOptions::new()
+                        .read(true)
+                        .open(path)
+                        .map_err(SourceError::SetupIOError)?,
+                ),
+            )
+        };
+
+        Ok(Self {
+            name,
+            stream: StreamDecoder::new(reader),
+        })
+    }
+}
+
+impl Iterator for PipeSource {
+    type Item = Result<TraceData, SourceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.stream
+            .next()
+            .map(|packets| packets.map_err(SourceError::IterIOError))
+    }
+}
+
+impl Source for PipeSource {
+    fn avail_buffer(&self) -> BufferStatus {
+        // A full pipe blocks the writer instead of dropping data.
+        BufferStatus::NotApplicable
+    }
+
+    fn record_raw(&mut self) {
+        self.stream.record_raw();
+    }
+
+    fn take_raw(&mut self) -> Option<Vec<u8>> {
+        self.stream.take_raw()
+    }
+
+    fn deframe(&mut self, demux: TpiuDemux) -> Result<(), SourceError> {
+        self.stream.deframe(demux);
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("pipe ({})", self.name)
+    }
+}
@@ -1,15 +1,19 @@
 use crate::manifest::ManifestProperties;
-use crate::sources::{Source, SourceError};
+use crate::sources::{stall::StallTimer, DecodeOptions, Source, SourceError, TpiuDemux};
 use crate::TraceData;
 
 use std::time::Duration;
 
-use itm_decode::{Decoder, DecoderOptions};
+use itm_decode::Decoder;
 use probe_rs::{architecture::arm::SwoConfig, Session};
 
 pub struct ProbeSource {
     session: Session,
+    cfg: SwoConfig,
     decoder: Decoder,
+    raw: Option<Vec<u8>>,
+    demux: Option<TpiuDemux>,
+    stall: Option<StallTimer>,
 }
 
 impl ProbeSource {
@@ -25,17 +29,13 @@ impl ProbeSource {
             .setup_swv(0, &cfg)
             .map_err(SourceError::SetupProbeError)?;
 
-        // Enable exception tracing
-        // {
-        //     let mut core = session.core(0)?;
-        //     let components = session.get_arm_components()?;
-        //     let mut dwt = Dwt::new(&mut core, find_component(components, PeripheralType::Dwt)?);
-        //     dwt.enable_exception_trace()?;
-        // }
-
         Ok(Self {
             session,
-            decoder: Decoder::new(DecoderOptions::default()),
+            cfg,
+            decoder: DecodeOptions::CAPTURE.decoder(),
+            raw: None,
+            demux: None,
+            stall: None,
         })
     }
 }
@@ -44,16 +44,36 @@ impl Iterator for ProbeSource {
     type Item = Result<TraceData, SourceError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // XXX we can get stuck here if read_swo returns data such that
-        // the Ok(None) is always pulled from the decoder.
+        // NOTE read_swo may keep returning data from which no packets
+        // are decoded, so without a stall timer this may loop forever.
         loop {
             match self.session.read_swo() {
                 Ok(bytes) => {
-                    self.decoder.push(&bytes);
+                    let bytes = match &mut self.demux {
+                        Some(demux) => match demux.push(&bytes) {
+                            Ok(itm) => itm,
+                            Err(e) => return Some(Err(SourceError::IterIOError(e))),
+                        },
+                        None => &bytes,
+                    };
+                    if let Some(raw) = &mut self.raw {
+                        raw.extend_from_slice(bytes);
+                    }
+                    self.decoder.push(bytes);
+
+                    if let Some(packets) = self.decoder.pull_with_timestamp() {
+                        if let Some(stall) = &mut self.stall {
+                            stall.feed();
+                        }
+                        return Some(Ok(packets));
+                    }
 
-                    match self.decoder.pull_with_timestamp() {
-                        None => continue,
-                        Some(packets) => return Some(Ok(packets)),
+                    if let Some(stall) = &mut self.stall {
+                        if stall.stalled() {
+                            let timeout = stall.timeout();
+                            stall.feed();
+                            return Some(Err(SourceError::Stalled(self.describe(), timeout)));
+                        }
                     }
                 }
                 Err(e) => return Some(Err(SourceError::IterProbeError(e))),
@@ -75,6 +95,30 @@ impl Source for ProbeSource {
         Ok(())
     }
 
+    fn deframe(&mut self, demux: TpiuDemux) -> Result<(), SourceError> {
+        // Have the probe keep the formatter frames.
+        self.cfg = self.cfg.set_continuous_formatting(true);
+        self.session
+            .setup_swv(0, &self.cfg)
+            .map_err(SourceError::SetupProbeError)?;
+        self.demux = Some(demux);
+
+        Ok(())
+    }
+
+    fn stall_after(&mut self, timeout: Duration) -> Result<(), SourceError> {
+        self.stall = Some(StallTimer::new(timeout));
+        Ok(())
+    }
+
+    fn record_raw(&mut self) {
+        self.raw.get_or_insert_with(Vec::new);
+    }
+
+    fn take_raw(&mut self) -> Option<Vec<u8>> {
+        self.raw.as_mut().map(std::mem::take)
+    }
+
     fn describe(&self) -> String {
         format!("probe (attached to {})", self.session.target().name)
     }
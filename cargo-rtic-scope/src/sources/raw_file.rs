@@ -1,24 +1,19 @@
-use crate::sources::{BufferStatus, Source, SourceError};
+use crate::sources::{stream::StreamDecoder, BufferStatus, Source, SourceError, TpiuDemux};
 use crate::TraceData;
 
 use std::fs;
-use std::io::Read;
-
-use itm_decode::{Decoder, DecoderOptions};
 
 /// Something data is deserialized from. Always a file.
 pub struct RawFileSource {
     file_name: String,
-    bytes: std::io::Bytes<fs::File>,
-    decoder: Decoder,
+    stream: StreamDecoder<fs::File>,
 }
 
 impl RawFileSource {
     pub fn new(fd: fs::File) -> Self {
         Self {
             file_name: format!("{:?}", fd),
-            bytes: fd.bytes(),
-            decoder: Decoder::new(DecoderOptions::default()),
+            stream: StreamDecoder::new(fd),
         }
     }
 }
@@ -27,19 +22,9 @@ impl Iterator for RawFileSource {
     type Item = Result<TraceData, SourceError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        for b in &mut self.bytes {
-            match b {
-                Ok(b) => self.decoder.push(&[b]),
-                Err(e) => return Some(Err(SourceError::IterIOError(e))),
-            };
-
-            match self.decoder.pull_with_timestamp() {
-                None => continue,
-                Some(packets) => return Some(Ok(packets)),
-            }
-        }
-
-        None
+        self.stream
+            .next()
+            .map(|packets| packets.map_err(SourceError::IterIOError))
     }
 }
 
@@ -48,6 +33,11 @@ impl Source for RawFileSource {
         BufferStatus::NotApplicable
     }
 
+    fn deframe(&mut self, demux: TpiuDemux) -> Result<(), SourceError> {
+        self.stream.deframe(demux);
+        Ok(())
+    }
+
     fn describe(&self) -> String {
         format!("raw file ({:?})", self.file_name)
     }
@@ -0,0 +1,34 @@
+use std::time::{Duration, Instant};
+
+/// Tracks how long a source has gone without decoding trace data, so
+/// that a stalled trace stream is reported instead of waited on
+/// forever.
+pub struct StallTimer {
+    timeout: Duration,
+    last: Option<Instant>,
+}
+
+impl StallTimer {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last: None,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Restarts the timer. Called whenever trace data is decoded.
+    pub fn feed(&mut self) {
+        self.last = Some(Instant::now());
+    }
+
+    /// Whether the timeout has passed since the timer was last fed.
+    /// The timer starts on the first check, i.e. once the source is
+    /// first read, rather than when the source is set up.
+    pub fn stalled(&mut self) -> bool {
+        self.last.get_or_insert_with(Instant::now).elapsed() >= self.timeout
+    }
+}
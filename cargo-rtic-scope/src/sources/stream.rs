@@ -0,0 +1,139 @@
+use crate::sources::{stall::StallTimer, tpiu::TpiuDemux, DecodeOptions};
+use crate::TraceData;
+
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read};
+use std::time::Duration;
+
+use itm_decode::Decoder;
+
+/// Size of the blocks read from the underlying reader.
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Decodes a raw trace stream read from a byte stream. The stream is
+/// read in blocks, each of which is pushed to the decoder in bulk; all
+/// packet groups decoded from a block are then yielded in turn.
+///
+/// A reader that resumes the stream after a discontinuity, e.g. after
+/// reconnecting, yields an error of kind [ErrorKind::ConnectionReset]
+/// before the resumed stream. The decoder and demultiplexer are then
+/// reset: bytes of a packet cut short are discarded, and TPIU frames are
+/// deframed only after the next synchronization packet.
+pub struct StreamDecoder<R> {
+    reader: R,
+    decoder: Decoder,
+    block: Box<[u8]>,
+    ready: VecDeque<TraceData>,
+    raw: Option<Vec<u8>>,
+    demux: Option<TpiuDemux>,
+    stall: Option<StallTimer>,
+    eof: bool,
+}
+
+impl<R: Read> StreamDecoder<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            decoder: DecodeOptions::CAPTURE.decoder(),
+            block: vec![0; BLOCK_SIZE].into_boxed_slice(),
+            ready: VecDeque::new(),
+            raw: None,
+            demux: None,
+            stall: None,
+            eof: false,
+        }
+    }
+
+    /// Returns the underlying reader.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Demultiplexes the stream with the given [TpiuDemux] before
+    /// decoding it.
+    pub fn deframe(&mut self, demux: TpiuDemux) {
+        self.demux = Some(demux);
+    }
+
+    /// Yields an error of kind [ErrorKind::TimedOut] once no packets
+    /// have been decoded for `timeout`. The reader must yield
+    /// [ErrorKind::WouldBlock] errors while no data arrives, lest it
+    /// blocks past the timeout.
+    pub fn stall_after(&mut self, timeout: Duration) {
+        self.stall = Some(StallTimer::new(timeout));
+    }
+
+    fn reset(&mut self) {
+        self.decoder = DecodeOptions::CAPTURE.decoder();
+        if let Some(demux) = &mut self.demux {
+            demux.reset();
+        }
+    }
+
+    /// Starts collecting the bytes decoded from the stream, i.e. after
+    /// demultiplexing, if enabled.
+    pub fn record_raw(&mut self) {
+        self.raw.get_or_insert_with(Vec::new);
+    }
+
+    /// Returns the bytes decoded since the last call, if collection has
+    /// been started via [StreamDecoder::record_raw]. Bytes are read
+    /// ahead of the yielded packet groups: all bytes of a block are
+    /// returned after the first packet group decoded from it.
+    pub fn take_raw(&mut self) -> Option<Vec<u8>> {
+        self.raw.as_mut().map(std::mem::take)
+    }
+}
+
+impl<R: Read> Iterator for StreamDecoder<R> {
+    type Item = io::Result<TraceData>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(packets) = self.ready.pop_front() {
+                return Some(Ok(packets));
+            }
+            if self.eof {
+                return None;
+            }
+            if let Some(stall) = &mut self.stall {
+                if stall.stalled() {
+                    stall.feed();
+                    return Some(Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        "no packets decoded from stream",
+                    )));
+                }
+            }
+
+            match self.reader.read(&mut self.block) {
+                Ok(0) => self.eof = true,
+                Ok(n) => {
+                    let bytes = match &mut self.demux {
+                        Some(demux) => match demux.push(&self.block[..n]) {
+                            Ok(itm) => itm,
+                            Err(e) => return Some(Err(e)),
+                        },
+                        None => &self.block[..n],
+                    };
+                    if let Some(raw) = &mut self.raw {
+                        raw.extend_from_slice(bytes);
+                    }
+                    self.decoder.push(bytes);
+                    while let Some(packets) = self.decoder.pull_with_timestamp() {
+                        self.ready.push_back(packets);
+                    }
+                    if !self.ready.is_empty() {
+                        if let Some(stall) = &mut self.stall {
+                            stall.feed();
+                        }
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => self.reset(),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use crate::log;
+use crate::sources::{stream::StreamDecoder, BufferStatus, Source, SourceError, TpiuDemux};
+use crate::TraceData;
+
+use std::io::{self, ErrorKind, Read};
+use std::net::TcpStream;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait between attempts to reconnect to the server. Also
+/// the interval at which a blocked read checks whether to halt.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether the error denotes a lost connection, after which the server
+/// may be reconnected to.
+fn is_connection_loss(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::TimedOut
+    )
+}
+
+/// A connection to a server that exposes the trace stream on a TCP
+/// port, such as the SWO port of an OpenOCD instance. Reading
+/// reconnects to the server if the connection is lost, until the halt
+/// flag is set, after which the stream ends. As the stream is then
+/// resumed at an arbitrary point, an error of kind
+/// [ErrorKind::ConnectionReset] is yielded upon reconnecting, see
+/// [StreamDecoder].
+struct Connection {
+    addr: String,
+    stream: TcpStream,
+    halt: Arc<AtomicBool>,
+}
+
+impl Connection {
+    fn connect(addr: &str) -> io::Result<TcpStream> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(RECONNECT_INTERVAL))?;
+        Ok(stream)
+    }
+
+    fn halted(&self) -> bool {
+        self.halt.load(Ordering::SeqCst)
+    }
+
+    /// Reconnects to the server. Returns `false` if halted before the
+    /// server could be reconnected to.
+    fn reconnect(&mut self) -> io::Result<bool> {
+        log::warn(format!("lost connection to {}; reconnecting...", self.addr));
+        loop {
+            thread::sleep(RECONNECT_INTERVAL);
+            if self.halted() {
+                return Ok(false);
+            }
+            match Self::connect(&self.addr) {
+                Ok(stream) => {
+                    self.stream = stream;
+                    break;
+                }
+                Err(e) if is_connection_loss(&e) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        log::status("Reconnected", format!("to {}", self.addr));
+
+        Ok(true)
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.halted() {
+                return Ok(0);
+            }
+            let lost = match self.stream.read(buf) {
+                Ok(0) => true,
+                Ok(n) => return Ok(n),
+                Err(e) if e.kind() == ErrorKind::Interrupted => false,
+                // Read timed out: check the halt flag and try again.
+                Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+                Err(e) if e.kind() == ErrorKind::TimedOut => false,
+                Err(e) if is_connection_loss(&e) => true,
+                Err(e) => return Err(e),
+            };
+            if lost {
+                if !self.reconnect()? {
+                    return Ok(0);
+                }
+                return Err(io::Error::new(
+                    ErrorKind::ConnectionReset,
+                    format!("reconnected to {}", self.addr),
+                ));
+            }
+        }
+    }
+}
+
+/// Something data is deserialized from. Always a TCP connection to a
+/// server that exposes the raw trace stream, e.g. OpenOCD.
+pub struct TcpSource {
+    addr: String,
+    stream: StreamDecoder<Connection>,
+}
+
+impl TcpSource {
+    /// Connects to the given `host:port`.
+    pub fn connect(addr: &str) -> Result<Self, SourceError> {
+        let stream = Connection::connect(addr).map_err(SourceError::SetupIOError)?;
+
+        Ok(Self {
+            addr: addr.to_string(),
+            stream: StreamDecoder::new(Connection {
+                addr: addr.to_string(),
+                stream,
+                halt: Arc::new(AtomicBool::new(false)),
+            }),
+        })
+    }
+}
+
+impl Iterator for TcpSource {
+    type Item = Result<TraceData, SourceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.stream
+            .next()
+            .map(|packets| packets.map_err(SourceError::IterIOError))
+    }
+}
+
+impl Source for TcpSource {
+    fn avail_buffer(&self) -> BufferStatus {
+        BufferStatus::NotApplicable
+    }
+
+    fn record_raw(&mut self) {
+        self.stream.record_raw();
+    }
+
+    fn take_raw(&mut self) -> Option<Vec<u8>> {
+        self.stream.take_raw()
+    }
+
+    fn halt_on(&mut self, halt: Arc<AtomicBool>) {
+        self.stream.get_mut().halt = halt;
+    }
+
+    fn deframe(&mut self, demux: TpiuDemux) -> Result<(), SourceError> {
+        self.stream.deframe(demux);
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("TCP ({})", self.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    use itm_decode::TracePacket;
+
+    /// A synchronization packet.
+    const SYNC: [u8; 6] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x80];
+    /// A local timestamp (format 2), which ends a packet group.
+    const LTS: u8 = 0x10;
+
+    /// A one-byte instrumentation packet on port 1.
+    fn instrumentation(payload: u8) -> [u8; 2] {
+        [0b0000_1001, payload]
+    }
+
+    /// Payloads of the instrumentation packets of a packet group.
+    fn payloads(data: TraceData) -> Vec<u8> {
+        data.packets
+            .into_iter()
+            .filter_map(|packet| match packet {
+                TracePacket::Instrumentation { port: 1, payload } => Some(payload),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    fn halt_after(halt: Arc<AtomicBool>, delay: Duration) {
+        thread::spawn(move || {
+            thread::sleep(delay);
+            halt.store(true, Ordering::SeqCst);
+        });
+    }
+
+    #[test]
+    fn decodes_across_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        // Serve one packet group per connection, closing the connection
+        // after each.
+        let server = thread::spawn(move || {
+            let mut first = SYNC.to_vec();
+            first.extend_from_slice(&instrumentation(0x2a));
+            first.push(LTS);
+            let mut second = instrumentation(0x2b).to_vec();
+            second.extend_from_slice(&instrumentation(0x2c));
+            second.push(LTS);
+
+            for capture in [first, second].iter() {
+                let (mut stream, _) = listener.accept().unwrap();
+                stream.write_all(capture).unwrap();
+            }
+        });
+
+        let mut source = TcpSource::connect(&addr).unwrap();
+        let halt = Arc::new(AtomicBool::new(false));
+        source.halt_on(halt.clone());
+
+        assert_eq!(payloads(source.next().unwrap().unwrap()), vec![0x2a]);
+        assert_eq!(payloads(source.next().unwrap().unwrap()), vec![0x2b, 0x2c]);
+        server.join().unwrap();
+
+        // The server is gone: reconnecting must end once halted.
+        halt_after(halt, Duration::from_millis(100));
+        assert!(source.next().is_none());
+    }
+
+    #[test]
+    fn resyncs_after_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        // Cut the first connection after the header of an
+        // instrumentation packet, before its payload.
+        let server = thread::spawn(move || {
+            let mut first = SYNC.to_vec();
+            first.push(instrumentation(0x2a)[0]);
+            let mut second = instrumentation(0x2b).to_vec();
+            second.push(LTS);
+
+            for capture in [first, second].iter() {
+                let (mut stream, _) = listener.accept().unwrap();
+                stream.write_all(capture).unwrap();
+            }
+        });
+
+        let mut source = TcpSource::connect(&addr).unwrap();
+        let halt = Arc::new(AtomicBool::new(false));
+        source.halt_on(halt.clone());
+        halt_after(halt, RECONNECT_INTERVAL * 3);
+
+        // Unless the decoder is reset, the header of the second packet
+        // is taken as the payload of the first, and no packet group is
+        // decoded before the source halts.
+        assert_eq!(payloads(source.next().unwrap().unwrap()), vec![0x2b]);
+        server.join().unwrap();
+        assert!(source.next().is_none());
+    }
+
+    #[test]
+    fn halts_while_idle() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let mut source = TcpSource::connect(&addr).unwrap();
+        let (_peer, _) = listener.accept().unwrap();
+        let halt = Arc::new(AtomicBool::new(false));
+        source.halt_on(halt.clone());
+
+        halt_after(halt, Duration::from_millis(100));
+        assert!(source.next().is_none());
+    }
+}
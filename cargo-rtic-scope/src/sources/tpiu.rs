@@ -0,0 +1,329 @@
+//! Removal of the TPIU formatter frame layer from a trace stream.
+//!
+//! When the TPIU formatter is enabled, the trace stream consists of
+//! 16-byte frames which multiplex the data of several trace sources,
+//! each identified by a 7-bit ID. In each frame, every even byte is
+//! either a data byte whose least significant bit is stored in the last
+//! byte of the frame, or a change of the source ID. Every odd byte is a
+//! data byte. Frames are aligned by full synchronization packets,
+//! `0x7fff_ffff`, which may also be inserted between frames.
+//!
+//! [TpiuDemux] feeds the data of the ITM to the decoder and writes the
+//! data of all other sources, e.g. the ETM, to files of their own.
+
+use crate::log;
+
+use std::collections::{btree_map::Entry, BTreeMap};
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// A full synchronization packet, as laid out in the trace stream.
+const FULL_SYNC: [u8; 4] = [0xff, 0xff, 0xff, 0x7f];
+
+const FRAME_LEN: usize = 16;
+
+/// Source ID of null data, used for padding.
+const NULL_ID: u8 = 0x00;
+
+/// Splits a TPIU-formatted trace stream into the data of each trace
+/// source.
+#[derive(Default)]
+pub struct TpiuDeframer {
+    synced: bool,
+    /// Bytes not yet forming a complete frame.
+    pending: Vec<u8>,
+    /// Source ID of the data currently being deframed.
+    current_id: u8,
+}
+
+impl TpiuDeframer {
+    /// Deframes the given bytes, calling `emit` with the source ID and
+    /// value of each data byte. Null data is dropped.
+    pub fn push(&mut self, bytes: &[u8], mut emit: impl FnMut(u8, u8)) {
+        self.pending.extend_from_slice(bytes);
+
+        let mut pos = 0;
+        loop {
+            let rest = &self.pending[pos..];
+
+            if !self.synced {
+                // Discard everything up to and including the first
+                // synchronization packet.
+                match rest.windows(FULL_SYNC.len()).position(|w| w == FULL_SYNC) {
+                    Some(i) => {
+                        pos += i + FULL_SYNC.len();
+                        self.synced = true;
+                    }
+                    None => {
+                        // Keep a potential partial synchronization
+                        // packet.
+                        pos += rest.len().saturating_sub(FULL_SYNC.len() - 1);
+                        break;
+                    }
+                }
+            } else if rest.starts_with(&FULL_SYNC) {
+                pos += FULL_SYNC.len();
+            } else if rest.len() >= FRAME_LEN {
+                // A synchronization packet cannot occur within a valid
+                // frame, as its leading bytes would change the source
+                // ID to the reserved 0x7f. Should one occur, bytes were
+                // lost: realign to it.
+                if let Some(i) = rest[..FRAME_LEN]
+                    .windows(FULL_SYNC.len())
+                    .position(|w| w == FULL_SYNC)
+                {
+                    pos += i;
+                    continue;
+                }
+
+                let mut frame = [0; FRAME_LEN];
+                frame.copy_from_slice(&rest[..FRAME_LEN]);
+                self.deframe(&frame, &mut emit);
+                pos += FRAME_LEN;
+            } else {
+                break;
+            }
+        }
+
+        self.pending.drain(..pos);
+    }
+
+    /// Discards any partial frame and the current source ID. Data is
+    /// only deframed again after the next synchronization packet.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    fn deframe(&mut self, frame: &[u8; FRAME_LEN], emit: &mut impl FnMut(u8, u8)) {
+        let aux = frame[FRAME_LEN - 1];
+
+        for i in 0..(FRAME_LEN / 2) {
+            let byte = frame[2 * i];
+            let aux_bit = (aux >> i) & 1;
+            // The data byte following an ID change, if any.
+            let next = if i < FRAME_LEN / 2 - 1 {
+                Some(frame[2 * i + 1])
+            } else {
+                None
+            };
+
+            if byte & 1 == 1 {
+                // A change of source ID. If the auxiliary bit is set,
+                // the change takes effect after the following byte.
+                let id = byte >> 1;
+                if aux_bit == 1 {
+                    if let Some(next) = next {
+                        self.emit(next, emit);
+                    }
+                    self.current_id = id;
+                } else {
+                    self.current_id = id;
+                    if let Some(next) = next {
+                        self.emit(next, emit);
+                    }
+                }
+            } else {
+                self.emit(byte | aux_bit, emit);
+                if let Some(next) = next {
+                    self.emit(next, emit);
+                }
+            }
+        }
+    }
+
+    fn emit(&self, byte: u8, emit: &mut impl FnMut(u8, u8)) {
+        if self.current_id != NULL_ID {
+            emit(self.current_id, byte);
+        }
+    }
+}
+
+/// Demultiplexes a TPIU-formatted trace stream: the data of the ITM is
+/// returned for decoding, while the data of every other trace source is
+/// written to `<others_dir>/tpiu-source-<id>.bin`, if a directory is
+/// given, or discarded.
+pub struct TpiuDemux {
+    deframer: TpiuDeframer,
+    itm_id: u8,
+    others_dir: Option<PathBuf>,
+    /// Files the data of other sources is written to, or `None` for
+    /// sources whose data is discarded.
+    others: BTreeMap<u8, Option<fs::File>>,
+    itm: Vec<u8>,
+    other: BTreeMap<u8, Vec<u8>>,
+}
+
+impl TpiuDemux {
+    /// Creates a demultiplexer that decodes the data of the trace
+    /// source with the given ID, usually the ATB ID of the ITM.
+    pub fn new(itm_id: u8, others_dir: Option<PathBuf>) -> Self {
+        Self {
+            deframer: TpiuDeframer::default(),
+            itm_id,
+            others_dir,
+            others: BTreeMap::new(),
+            itm: vec![],
+            other: BTreeMap::new(),
+        }
+    }
+
+    /// Demultiplexes the given bytes and returns the data of the ITM
+    /// therein.
+    pub fn push(&mut self, bytes: &[u8]) -> io::Result<&[u8]> {
+        let Self {
+            deframer,
+            itm_id,
+            itm,
+            other,
+            ..
+        } = self;
+        itm.clear();
+        deframer.push(bytes, |id, byte| {
+            if id == *itm_id {
+                itm.push(byte);
+            } else {
+                other.entry(id).or_default().push(byte);
+            }
+        });
+
+        for (id, data) in std::mem::take(&mut self.other) {
+            if let Some(file) = self.other_file(id)? {
+                file.write_all(&data)?;
+            }
+        }
+
+        Ok(&self.itm)
+    }
+
+    /// Resynchronizes after a discontinuity in the stream, see
+    /// [TpiuDeframer::reset].
+    pub fn reset(&mut self) {
+        self.deframer.reset();
+    }
+
+    /// Returns the file the data of the given source is written to,
+    /// creating it on first use.
+    fn other_file(&mut self, id: u8) -> io::Result<Option<&mut fs::File>> {
+        let file = match self.others.entry(id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match &self.others_dir {
+                Some(dir) => {
+                    fs::create_dir_all(dir)?;
+                    let path = dir.join(format!("tpiu-source-{}.bin", id));
+                    log::status(
+                        "Demuxing",
+                        format!("trace source {} to {}", id, path.display()),
+                    );
+                    entry.insert(Some(fs::File::create(path)?))
+                }
+                None => {
+                    log::warn(format!(
+                        "discarding the data of trace source {}; pass --tpiu-others-dir to keep it",
+                        id
+                    ));
+                    entry.insert(None)
+                }
+            },
+        };
+
+        Ok(file.as_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame with an immediate change to source 1, a delayed change
+    /// to source 2, and a change to null data that pads the rest.
+    #[rustfmt::skip]
+    const FRAME: [u8; FRAME_LEN] = [
+        0x03, 0xa0, // source 1, immediately
+        0x42, 0x11, // 0x42: LSB set by aux bit 1
+        0x05, 0x22, // source 2, after 0x22 (aux bit 2)
+        0x44, 0x55,
+        0x01, 0x66, // null data
+        0x00, 0x00,
+        0x00, 0x00,
+        0x00,
+        0b0000_0110, // aux bits
+    ];
+
+    /// Data of [FRAME], as (source ID, byte).
+    const DATA: [(u8, u8); 6] = [
+        (1, 0xa0),
+        (1, 0x43),
+        (1, 0x11),
+        (1, 0x22),
+        (2, 0x44),
+        (2, 0x55),
+    ];
+
+    fn deframe(deframer: &mut TpiuDeframer, bytes: &[u8]) -> Vec<(u8, u8)> {
+        let mut data = vec![];
+        deframer.push(bytes, |id, byte| data.push((id, byte)));
+        data
+    }
+
+    #[test]
+    fn id_changes() {
+        let mut deframer = TpiuDeframer::default();
+        let stream = [&FULL_SYNC[..], &FRAME].concat();
+
+        assert_eq!(deframe(&mut deframer, &stream), DATA);
+    }
+
+    #[test]
+    fn frame_split_across_pushes() {
+        let mut deframer = TpiuDeframer::default();
+        let stream = [&FULL_SYNC[..], &FRAME, &FULL_SYNC, &FRAME].concat();
+        let (first, second) = stream.split_at(FULL_SYNC.len() + 7);
+
+        assert!(deframe(&mut deframer, first).is_empty());
+        assert_eq!(deframe(&mut deframer, second), [DATA, DATA].concat());
+    }
+
+    #[test]
+    fn realigns_after_lost_bytes() {
+        let mut deframer = TpiuDeframer::default();
+        // Garbage before the first synchronization packet, and a frame
+        // of which all but five bytes were lost.
+        let stream = [
+            &[0x12, 0x34][..],
+            &FULL_SYNC,
+            &FRAME,
+            &FRAME[..5],
+            &FULL_SYNC,
+            &FRAME,
+        ]
+        .concat();
+
+        assert_eq!(deframe(&mut deframer, &stream), [DATA, DATA].concat());
+    }
+
+    #[test]
+    fn resyncs_after_reset() {
+        let mut deframer = TpiuDeframer::default();
+        let stream = [&FULL_SYNC[..], &FRAME].concat();
+
+        // A frame cut short, followed by a frame of the resumed stream.
+        assert!(deframe(&mut deframer, &stream[..FULL_SYNC.len() + 7]).is_empty());
+        deframer.reset();
+        assert!(deframe(&mut deframer, &FRAME).is_empty());
+        assert_eq!(deframe(&mut deframer, &stream), DATA);
+    }
+
+    #[test]
+    fn demux() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut demux = TpiuDemux::new(1, Some(dir.path().to_path_buf()));
+        let stream = [&FULL_SYNC[..], &FRAME].concat();
+
+        assert_eq!(demux.push(&stream).unwrap(), [0xa0, 0x43, 0x11, 0x22]);
+        assert_eq!(
+            fs::read(dir.path().join("tpiu-source-2.bin")).unwrap(),
+            [0x44, 0x55]
+        );
+    }
+}
@@ -1,15 +1,16 @@
-use crate::sources::{BufferStatus, Source, SourceError};
+use crate::sources::{stream::StreamDecoder, BufferStatus, Source, SourceError, TpiuDemux};
 use crate::TraceData;
 
 use std::fs;
-use std::io::Read;
+use std::io::{self, ErrorKind, Read};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::time::Duration;
 
-use itm_decode::{Decoder, DecoderOptions};
 use nix::{
+    errno::Errno,
     fcntl::{self, FcntlArg, OFlag},
     libc,
+    poll::{poll, PollFd, PollFlags},
     sys::termios::{
         self, BaudRate, ControlFlags, InputFlags, LocalFlags, OutputFlags, SetArg,
         SpecialCharacterIndices as CC,
@@ -27,19 +28,99 @@ mod ioctl {
     ioctl_read_bad!(fionread, libc::FIONREAD, libc::c_int);
     ioctl_write_ptr_bad!(tiocmset, libc::TIOCMSET, libc::c_int);
     ioctl_write_int_bad!(tcflsh, libc::TCFLSH);
+    ioctl_read_bad!(tcgets2, libc::TCGETS2, libc::termios2);
+    ioctl_write_ptr_bad!(tcsets2, libc::TCSETS2, libc::termios2);
 }
 
-/// Opens and configures the given `device`.
+/// Largest relative deviation from the requested baud rate that is
+/// accepted. Beyond this, UART framing errors are to be expected.
+pub const BAUD_RATE_TOLERANCE: f64 = 0.02;
+
+/// Maps a baud rate to its [BaudRate], if it is a standard rate.
+fn standard_baud_rate(baud: u32) -> Option<BaudRate> {
+    Some(match baud {
+        50 => BaudRate::B50,
+        75 => BaudRate::B75,
+        110 => BaudRate::B110,
+        134 => BaudRate::B134,
+        150 => BaudRate::B150,
+        200 => BaudRate::B200,
+        300 => BaudRate::B300,
+        600 => BaudRate::B600,
+        1200 => BaudRate::B1200,
+        1800 => BaudRate::B1800,
+        2400 => BaudRate::B2400,
+        4800 => BaudRate::B4800,
+        9600 => BaudRate::B9600,
+        19200 => BaudRate::B19200,
+        38400 => BaudRate::B38400,
+        57600 => BaudRate::B57600,
+        115200 => BaudRate::B115200,
+        230400 => BaudRate::B230400,
+        460800 => BaudRate::B460800,
+        500000 => BaudRate::B500000,
+        576000 => BaudRate::B576000,
+        921600 => BaudRate::B921600,
+        1000000 => BaudRate::B1000000,
+        1152000 => BaudRate::B1152000,
+        1500000 => BaudRate::B1500000,
+        2000000 => BaudRate::B2000000,
+        2500000 => BaudRate::B2500000,
+        3000000 => BaudRate::B3000000,
+        3500000 => BaudRate::B3500000,
+        4000000 => BaudRate::B4000000,
+        _ => return None,
+    })
+}
+
+/// Applies the given baud rate to `fd`, unless it is a standard rate
+/// that was already applied via [termios::cfsetspeed]. Non-standard
+/// rates are applied via the termios2 interface. Verifies that the rate
+/// the device settled on is close enough to the requested rate.
+unsafe fn apply_baud_rate(fd: RawFd, device: &str, baud: u32) -> Result<(), SourceError> {
+    let rejected = |reason: String| SourceError::BaudRateRejected(device.to_string(), baud, reason);
+    let read_back = |tio: &mut libc::termios2| {
+        ioctl::tcgets2(fd, tio).map_err(|e| {
+            SourceError::SetupError(format!(
+                "Failed to read terminal settings of {}: TCGETS2 = {}",
+                device, e
+            ))
+        })
+    };
+
+    let mut tio: libc::termios2 = std::mem::zeroed();
+    read_back(&mut tio)?;
+
+    if standard_baud_rate(baud).is_none() {
+        tio.c_cflag &= !libc::CBAUD;
+        tio.c_cflag |= libc::BOTHER;
+        tio.c_ispeed = baud;
+        tio.c_ospeed = baud;
+        ioctl::tcsets2(fd, &tio).map_err(|e| rejected(format!("TCSETS2 = {}", e)))?;
+        read_back(&mut tio)?;
+    }
+
+    // The driver settles on the closest rate it can generate.
+    let actual = tio.c_ispeed;
+    if (actual as f64 - baud as f64).abs() > baud as f64 * BAUD_RATE_TOLERANCE {
+        return Err(rejected(format!("closest supported rate is {}", actual)));
+    }
+
+    Ok(())
+}
+
+/// Opens and configures the given `device` to receive at the given
+/// `baud` rate, which need not be a standard rate.
 ///
 /// Effectively mirrors the behavior of
 /// ```
 /// $ screen /dev/ttyUSB3 115200
 /// ```
-/// assuming that `device` is `/dev/ttyUSB3`.
+/// assuming that `device` is `/dev/ttyUSB3` and `baud` is 115200.
 ///
 /// TODO ensure POSIX compliance, see termios(3)
 /// TODO We are currently using line disciple 0. Is that correct?
-pub fn configure(device: &String) -> Result<fs::File, SourceError> {
+pub fn configure(device: &str, baud: u32) -> Result<fs::File, SourceError> {
     let file = fs::OpenOptions::new()
         .read(true)
         .open(&device)
@@ -142,7 +223,12 @@ pub fn configure(device: &String) -> Result<fs::File, SourceError> {
             | LocalFlags::PENDIN
             | LocalFlags::NOFLSH);
 
-        termios::cfsetspeed(&mut settings, BaudRate::B115200).map_err(|e| {
+        // Non-standard rates are applied after the other settings.
+        termios::cfsetspeed(
+            &mut settings,
+            standard_baud_rate(baud).unwrap_or(BaudRate::B38400),
+        )
+        .map_err(|e| {
             SourceError::SetupError(format!(
                 "Failed to configure {} baud rate: cfsetspeed = {}",
                 device, e
@@ -160,6 +246,8 @@ pub fn configure(device: &String) -> Result<fs::File, SourceError> {
             ))
         })?;
 
+        apply_baud_rate(fd, device, baud)?;
+
         let mut flags: libc::c_int = 0;
         ioctl::tiocmget(fd, &mut flags).map_err(|e| {
             SourceError::SetupError(format!(
@@ -192,20 +280,41 @@ pub fn configure(device: &String) -> Result<fs::File, SourceError> {
     Ok(file)
 }
 
+/// How long a read waits for data before giving up, so that a silent
+/// device does not block the reader indefinitely.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A configured device that yields [ErrorKind::WouldBlock] if no data
+/// arrives within [POLL_INTERVAL]. Reads otherwise block until the
+/// first byte arrives.
+struct PolledDevice(fs::File);
+
+impl Read for PolledDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut fds = [PollFd::new(self.0.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, POLL_INTERVAL.as_millis() as i32) {
+            Ok(0) => Err(ErrorKind::WouldBlock.into()),
+            Ok(_) => self.0.read(buf),
+            Err(nix::Error::Sys(Errno::EINTR)) => Err(ErrorKind::Interrupted.into()),
+            Err(e) => Err(io::Error::new(ErrorKind::Other, e)),
+        }
+    }
+}
+
 pub struct TTYSource {
-    bytes: std::io::Bytes<fs::File>,
+    stream: StreamDecoder<PolledDevice>,
     fd: RawFd,
-    decoder: Decoder,
     session: Session,
+    stall_timeout: Option<Duration>,
 }
 
 impl TTYSource {
     pub fn new(device: fs::File, session: Session) -> Self {
         Self {
             fd: device.as_raw_fd(),
-            bytes: device.bytes(),
-            decoder: Decoder::new(DecoderOptions::default()),
+            stream: StreamDecoder::new(PolledDevice(device)),
             session,
+            stall_timeout: None,
         }
     }
 }
@@ -214,19 +323,14 @@ impl Iterator for TTYSource {
     type Item = Result<TraceData, SourceError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        for b in &mut self.bytes {
-            match b {
-                Ok(b) => self.decoder.push(&[b]),
-                Err(e) => return Some(Err(SourceError::IterIOError(e))),
-            };
-
-            match self.decoder.pull_with_timestamp() {
-                None => continue,
-                Some(packets) => return Some(Ok(packets)),
-            }
-        }
-
-        None
+        self.stream.next().map(|packets| {
+            packets.map_err(|e| match (e.kind(), self.stall_timeout) {
+                (ErrorKind::TimedOut, Some(timeout)) => {
+                    SourceError::Stalled(self.describe(), timeout)
+                }
+                _ => SourceError::IterIOError(e),
+            })
+        })
     }
 }
 
@@ -263,6 +367,25 @@ impl Source for TTYSource {
         }
     }
 
+    fn stall_after(&mut self, timeout: Duration) -> Result<(), SourceError> {
+        self.stream.stall_after(timeout);
+        self.stall_timeout = Some(timeout);
+        Ok(())
+    }
+
+    fn record_raw(&mut self) {
+        self.stream.record_raw();
+    }
+
+    fn take_raw(&mut self) -> Option<Vec<u8>> {
+        self.stream.take_raw()
+    }
+
+    fn deframe(&mut self, demux: TpiuDemux) -> Result<(), SourceError> {
+        self.stream.deframe(demux);
+        Ok(())
+    }
+
     fn describe(&self) -> String {
         format!("TTY (fd: {})", self.fd)
     }
@@ -0,0 +1,140 @@
+//! Measures the throughput of the replay pipeline in-process: decoding
+//! a raw trace stream, mapping its events, and forwarding them to the
+//! session analysis and the sinks. The stream is synthetic, so that no
+//! target, application build, or capture is needed:
+//!
+//! ```text
+//! cargo test --release throughput -- --ignored --nocapture
+//! ```
+//!
+//! The pipeline is measured without sinks, and with one and two export
+//! sinks, which shows the cost of each additional sink. To compare
+//! revisions, run the benchmark on each.
+use crate::analysis::SessionAnalysis;
+use crate::recovery::Metadata;
+use crate::sinks::{self, Packets, Sink, SinkWorker};
+use crate::sources::RawFileSource;
+
+use std::io::{Seek, SeekFrom, Write};
+use std::sync::{atomic::AtomicBool, Arc};
+use std::time::{Duration, Instant};
+
+/// Size of the synthetic raw trace stream.
+const STREAM_SIZE: usize = 16 * 1024 * 1024;
+
+/// Number of times each configuration is measured.
+const ITERATIONS: usize = 5;
+
+const METADATA: &str = r#"{
+    "maps": {
+        "exceptions": { "PendSV": ["app", "blink"], "SysTick": ["app", "tick"] },
+        "interrupts": {},
+        "sw_assocs": {}
+    },
+    "manip": {
+        "pac_name": "stm32f4",
+        "pac_version": "0.13",
+        "pac_features": [],
+        "interrupt_path": "stm32f4::stm32f401::Interrupt",
+        "tpiu_freq": 16000000,
+        "tpiu_baud": 115200,
+        "dwt_enter_id": 1,
+        "dwt_exit_id": 2
+    },
+    "timestamp": "2021-11-01T12:00:00+01:00",
+    "freq": 16000000,
+    "comment": null
+}"#;
+
+/// A raw trace stream in which app::tick (SysTick) is repeatedly
+/// entered and preempted by app::blink (PendSV), each exception trace
+/// packet followed by a local timestamp.
+fn stream() -> Vec<u8> {
+    const SYNC: [u8; 6] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x80];
+    const ITERATION: [u8; 24] = [
+        0x0e, 0x0f, 0x10, 0x10, // SysTick entered
+        0x0e, 0x0e, 0x10, 0x20, // PendSV entered
+        0x0e, 0x0e, 0x20, 0x30, // PendSV exited
+        0x0e, 0x0f, 0x30, 0x10, // SysTick returned
+        0x0e, 0x0f, 0x20, 0x20, // SysTick exited
+        0x0e, 0x00, 0x30, 0x30, // ThreadMode returned
+    ];
+
+    let mut stream = SYNC.to_vec();
+    while stream.len() < STREAM_SIZE {
+        stream.extend_from_slice(&ITERATION);
+    }
+    stream
+}
+
+/// Replays `raw` through the pipeline with the sinks created by
+/// `create_sinks`, and returns how long it took for the analysis and all sinks
+/// to finish.
+fn replay(
+    raw: &std::fs::File,
+    metadata: &Arc<Metadata>,
+    create_sinks: &dyn Fn(&Metadata) -> Vec<Box<dyn Sink>>,
+) -> Duration {
+    let mut raw = raw.try_clone().unwrap();
+    raw.seek(SeekFrom::Start(0)).unwrap();
+    let halt = Arc::new(AtomicBool::new(false));
+
+    let start = Instant::now();
+    let source = RawFileSource::new(raw);
+    let mut analyzer = SessionAnalysis::spawn(metadata.constraints().clone(), None, halt.clone());
+    let mut workers: Vec<SinkWorker> = create_sinks(metadata)
+        .into_iter()
+        .map(|sink| SinkWorker::spawn(sink, false, halt.clone()))
+        .collect();
+
+    for data in source {
+        let packets = Arc::new(Packets::new(data.unwrap(), None, metadata.clone()));
+        analyzer.send(packets.clone());
+        for worker in workers.iter_mut() {
+            assert!(worker.send(packets.clone()));
+        }
+    }
+    for worker in workers.drain(..) {
+        worker.finish();
+    }
+    analyzer.finish();
+
+    start.elapsed()
+}
+
+#[test]
+#[ignore]
+fn throughput() {
+    let mut raw = tempfile::tempfile().unwrap();
+    raw.write_all(&stream()).unwrap();
+    let metadata: Arc<Metadata> = Arc::new(serde_json::from_str(METADATA).unwrap());
+    let out = tempfile::tempdir().unwrap();
+    let create = |name: &str| std::fs::File::create(out.path().join(name)).unwrap();
+
+    let chrome = |metadata: &Metadata| -> Box<dyn Sink> {
+        Box::new(sinks::ChromeSink::new(create("trace.json"), metadata).unwrap())
+    };
+    let vcd = |metadata: &Metadata| -> Box<dyn Sink> {
+        Box::new(sinks::VcdSink::new(create("trace.vcd"), metadata).unwrap())
+    };
+    let configurations: [(&str, &dyn Fn(&Metadata) -> Vec<Box<dyn Sink>>); 3] = [
+        ("no sinks", &|_| vec![]),
+        ("1 export", &|m| vec![chrome(m)]),
+        ("2 exports", &|m| vec![chrome(m), vcd(m)]),
+    ];
+
+    for (name, create_sinks) in configurations.iter() {
+        let mut times: Vec<Duration> = (0..ITERATIONS)
+            .map(|_| replay(&raw, &metadata, *create_sinks))
+            .collect();
+        times.sort();
+        let median = times[times.len() / 2];
+
+        println!(
+            "{:<12} {:>8.3} s ({:.1} MiB/s)",
+            name,
+            median.as_secs_f64(),
+            STREAM_SIZE as f64 / (1024.0 * 1024.0) / median.as_secs_f64().max(f64::EPSILON),
+        );
+    }
+}
@@ -47,6 +47,9 @@
 /// function. Refer to crate example usage.
 pub use rtic_trace_macros::trace;
 
+// NOTE(repr) cargo-rtic-scope --host-setup expects `exit` to follow
+// `enter`.
+#[repr(C)]
 struct WatchVars {
     /// Watch variable to which the just entered software task ID is written to.
     enter: u32,
@@ -100,6 +103,15 @@ pub mod setup {
         });
     }
 
+    /// Enables the TPIU formatter, which [core_peripherals] disables, so
+    /// that other trace sources (e.g. the ETM) may share the trace port
+    /// with the ITM. Should be called after [core_peripherals]. The
+    /// formatted trace stream is decoded by passing `--tpiu-source-id 1`
+    /// to `cargo rtic-scope`.
+    pub fn enable_tpiu_formatting(tpiu: &mut Core::TPIU) {
+        tpiu.enable_continuous_formatting(true);
+    }
+
     /// Configures all related device peripherals for RTIC task tracing.
     pub fn device_peripherals(dbgmcu: &mut Device::DBGMCU) {
         #[rustfmt::skip]